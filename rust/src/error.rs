@@ -0,0 +1,182 @@
+//! The error type shared by the capstone steps
+
+// JSON-RPC error codes from Bitcoin Core's `rpc/protocol.h` that the steps react to
+pub const RPC_METHOD_NOT_FOUND: i32 = -32601;
+pub const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+pub const RPC_IN_WARMUP: i32 = -28;
+pub const RPC_WALLET_ALREADY_LOADED: i32 = -35;
+pub const RPC_WALLET_ALREADY_EXISTS: i32 = -36;
+
+/// JSON-RPC error code the node answered with, if `err` is an error response rather than a
+/// transport or decoding failure
+pub fn rpc_error_code(err: &bitcoincore_rpc::Error) -> Option<i32> {
+    match err {
+        bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Rpc(e)) => Some(e.code),
+        _ => None,
+    }
+}
+
+/// Why a capstone run failed, split by stage so a misconfigured node is told apart from bad input
+pub enum CapstoneError {
+    /// The node could not be reached or rejected a call
+    Rpc(bitcoincore_rpc::Error),
+    /// Reading or writing a local file failed
+    Io(std::io::Error),
+    /// A command-line option, environment variable or file held an invalid value
+    Parse(String),
+    /// A BTC amount was finer than one satoshi, which no transaction can hold
+    SubSatoshi { value: String },
+    /// The mining address or coinbase descriptor is malformed or belongs to another network
+    InvalidMiningAddress { address: String, reason: String },
+    /// Creating, loading or connecting to a wallet failed
+    WalletSetup(String),
+    /// The node has pruned a block the report needs
+    BlockPruned { block_hash: String, message: String },
+    /// A check on the transaction or the written report did not hold
+    Verification(String),
+    /// Mining more blocks would pass the `MAX_BLOCKS` safety cap
+    MiningCapExceeded { mined: u64, next: u64, cap: u64 },
+    /// `--only` needs something an earlier phase records, but no run has recorded it yet
+    MissingState { what: String, phase: String },
+    /// Another failure, with the tail of the node's debug.log that usually explains it better
+    WithNodeLog {
+        error: Box<CapstoneError>,
+        debug_log_tail: String,
+    },
+}
+
+impl CapstoneError {
+    /// Attach the tail of the node's debug.log to this error
+    pub fn with_node_log(self, debug_log_tail: String) -> Self {
+        CapstoneError::WithNodeLog {
+            error: Box::new(self),
+            debug_log_tail,
+        }
+    }
+}
+
+impl std::fmt::Display for CapstoneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CapstoneError::Rpc(e) => write!(f, "RPC error: {e}"),
+            CapstoneError::Io(e) => write!(f, "I/O error: {e}"),
+            CapstoneError::Parse(message) => write!(f, "Invalid input: {message}"),
+            CapstoneError::SubSatoshi { value } => {
+                write!(f, "Amount {value} BTC has sub-satoshi precision")
+            }
+            CapstoneError::InvalidMiningAddress { address, reason } => {
+                write!(f, "Cannot mine to '{address}': {reason}")
+            }
+            CapstoneError::WalletSetup(message) => write!(f, "Wallet setup failed: {message}"),
+            CapstoneError::Verification(message) => write!(f, "Verification failed: {message}"),
+            CapstoneError::BlockPruned {
+                block_hash,
+                message,
+            } => write!(
+                f,
+                "Block {block_hash} has been pruned by the node ({message}); pass the block hash of an \
+                 unpruned block, or restart the node with -reindex to fetch it again"
+            ),
+            CapstoneError::MiningCapExceeded { mined, next, cap } => write!(
+                f,
+                "Mining cap exceeded: mining {next} more block(s) after {mined} would pass MAX_BLOCKS={cap}"
+            ),
+            CapstoneError::MissingState { what, phase } => write!(
+                f,
+                "No {what} has been recorded yet; run the '{phase}' phase first"
+            ),
+            CapstoneError::WithNodeLog {
+                error,
+                debug_log_tail,
+            } => write!(f, "{error}\n--- node debug.log ---\n{debug_log_tail}"),
+        }
+    }
+}
+
+// `main` returning an error prints its `Debug` form, so make that the readable message too
+impl std::fmt::Debug for CapstoneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for CapstoneError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CapstoneError::Rpc(e) => Some(e),
+            CapstoneError::Io(e) => Some(e),
+            CapstoneError::WithNodeLog { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<bitcoincore_rpc::Error> for CapstoneError {
+    fn from(err: bitcoincore_rpc::Error) -> Self {
+        match err {
+            bitcoincore_rpc::Error::Io(e) => CapstoneError::Io(e),
+            other => CapstoneError::Rpc(other),
+        }
+    }
+}
+
+impl From<std::io::Error> for CapstoneError {
+    fn from(err: std::io::Error) -> Self {
+        CapstoneError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for CapstoneError {
+    fn from(err: serde_json::Error) -> Self {
+        CapstoneError::Parse(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc_error(code: i32) -> bitcoincore_rpc::Error {
+        bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Rpc(
+            bitcoincore_rpc::jsonrpc::error::RpcError {
+                code,
+                message: "Wallet already exists".to_string(),
+                data: None,
+            },
+        ))
+    }
+
+    #[test]
+    fn with_node_log_keeps_the_error_and_appends_the_log() {
+        let err = CapstoneError::Verification("fee mismatch".to_string())
+            .with_node_log("2026-10-16T00:00:00Z mempool full".to_string());
+        assert_eq!(
+            err.to_string(),
+            "Verification failed: fee mismatch\n--- node debug.log ---\n2026-10-16T00:00:00Z mempool full"
+        );
+        let source = std::error::Error::source(&err).unwrap();
+        assert_eq!(source.to_string(), "Verification failed: fee mismatch");
+    }
+
+    #[test]
+    fn rpc_error_code_reads_json_rpc_errors() {
+        assert_eq!(
+            rpc_error_code(&rpc_error(RPC_WALLET_ALREADY_EXISTS)),
+            Some(RPC_WALLET_ALREADY_EXISTS)
+        );
+    }
+
+    #[test]
+    fn rpc_error_code_ignores_transport_errors() {
+        let err = bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Transport(
+            "connection refused".into(),
+        ));
+        assert_eq!(rpc_error_code(&err), None);
+    }
+
+    #[test]
+    fn rpc_error_code_ignores_non_rpc_errors() {
+        let err = bitcoincore_rpc::Error::ReturnedError("already exists".to_string());
+        assert_eq!(rpc_error_code(&err), None);
+    }
+}
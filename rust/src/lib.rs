@@ -0,0 +1,17 @@
+//! The capstone steps as a library: wallet setup, mining, the out.txt report and the checks run
+//! against the chain.
+//! The binary in `main.rs` orchestrates them into the full Miner-to-Trader scenario; with the
+//! `async` feature, `rpc_async` runs the default scenario over an async transport.
+
+mod error;
+pub mod mining;
+pub mod report;
+#[cfg(feature = "async")]
+pub mod rpc_async;
+pub mod verify;
+pub mod wallet;
+
+pub use error::{
+    rpc_error_code, CapstoneError, RPC_INVALID_ADDRESS_OR_KEY, RPC_IN_WARMUP, RPC_METHOD_NOT_FOUND,
+    RPC_WALLET_ALREADY_EXISTS, RPC_WALLET_ALREADY_LOADED,
+};
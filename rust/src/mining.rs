@@ -0,0 +1,450 @@
+//! Mining blocks until the Miner has coins it can spend
+
+use crate::verify::verify_block_composition;
+use crate::wallet::checked_address;
+use crate::CapstoneError;
+use bitcoin::{Address, Network};
+use bitcoincore_rpc::bitcoin::Amount;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Confirmations a coinbase output needs before it can be spent, on every chain in `MATURITY_CHAINS`
+pub const COINBASE_MATURITY: u64 = 100;
+pub const MATURITY_CHAINS: &[&str] = &["main", "test", "testnet4", "signet", "regtest"];
+
+// The wallet only counts a coinbase as spendable one confirmation later, once a block spending it
+// could be mined on top of the tip
+pub const WALLET_COINBASE_MATURITY: u64 = COINBASE_MATURITY + 1;
+
+// Most blocks a single run may mine unless `MAX_BLOCKS` says otherwise, as a guard against runaway mining
+pub const DEFAULT_MAX_BLOCKS: u64 = 500;
+
+// Past maturity, blocks are mined in chunks of this size while a second thread polls the balance
+// every `BALANCE_POLL_INTERVAL`; the whole top-up gives up after `TOP_UP_TIMEOUT`
+pub const MINING_CHUNK_BLOCKS: u64 = 10;
+pub const BALANCE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+pub const TOP_UP_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// Regtest halves the block subsidy every 150 blocks (mainnet uses 210,000)
+pub const REGTEST_HALVING_INTERVAL: u64 = 150;
+pub const INITIAL_BLOCK_SUBSIDY: Amount = Amount::from_sat(50 * 100_000_000);
+
+// Size past which the block log is moved aside at startup, unless `BLOCK_LOG_MAX_BYTES` says otherwise
+pub const DEFAULT_BLOCK_LOG_MAX_BYTES: u64 = 1024 * 1024;
+
+/// Append-only record of every block hash a run mines, one `<unix time> <hash>` line per block
+pub struct BlockLog {
+    file: File,
+}
+
+impl BlockLog {
+    /// Open `path` for appending, first rotating it to `<path>.1` if it has grown past `max_bytes`
+    pub fn open(path: &Path, max_bytes: u64) -> std::io::Result<Self> {
+        if std::fs::metadata(path).is_ok_and(|meta| meta.len() > max_bytes) {
+            let mut rotated = path.as_os_str().to_owned();
+            rotated.push(".1");
+            std::fs::rename(path, &rotated)?;
+            log::info!(
+                "Rotated {} to {}",
+                path.display(),
+                Path::new(&rotated).display()
+            );
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(BlockLog { file })
+    }
+
+    /// Append `hashes`, all stamped with the current time
+    pub fn record(&self, hashes: &[String]) -> std::io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let lines: String = hashes
+            .iter()
+            .map(|hash| format!("{now} {hash}\n"))
+            .collect();
+        // One write per batch, so an interrupted run never leaves half a line behind
+        (&self.file).write_all(lines.as_bytes())
+    }
+}
+
+/// One block mined by this tool
+#[derive(Clone, Debug, Serialize)]
+pub struct MiningEvent {
+    pub height: u64,
+    pub hash: String,
+    pub reward_address: String,
+}
+
+/// Where every mined block is reported: always the block log, plus a channel whose receiver
+/// forwards `MiningEvent`s to whoever is listening
+pub struct MiningEvents {
+    block_log: BlockLog,
+    sender: Option<Sender<MiningEvent>>,
+}
+
+impl MiningEvents {
+    pub fn new(block_log: BlockLog, sender: Option<Sender<MiningEvent>>) -> Self {
+        MiningEvents { block_log, sender }
+    }
+
+    /// Report `hashes`, just mined in order to `reward_address` and ending at the chain tip
+    pub fn blocks_mined(
+        &self,
+        rpc: &Client,
+        hashes: &[String],
+        reward_address: &str,
+    ) -> bitcoincore_rpc::Result<()> {
+        self.block_log.record(hashes)?;
+        let Some(sender) = &self.sender else {
+            return Ok(());
+        };
+        let first_height = (rpc.get_block_count()? + 1).saturating_sub(hashes.len() as u64);
+        for (height, hash) in (first_height..).zip(hashes) {
+            let event = MiningEvent {
+                height,
+                hash: hash.clone(),
+                reward_address: reward_address.to_string(),
+            };
+            // Nobody listening any more is not a reason to stop mining
+            if sender.send(event).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Size limit for the block log, from `BLOCK_LOG_MAX_BYTES` or `DEFAULT_BLOCK_LOG_MAX_BYTES`
+pub fn block_log_max_bytes() -> Result<u64, CapstoneError> {
+    match std::env::var("BLOCK_LOG_MAX_BYTES") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|e| CapstoneError::Parse(format!("Invalid BLOCK_LOG_MAX_BYTES value: {e}"))),
+        Err(_) => Ok(DEFAULT_BLOCK_LOG_MAX_BYTES),
+    }
+}
+
+/// Cap on the blocks one run may mine, from `MAX_BLOCKS` or `DEFAULT_MAX_BLOCKS`
+pub fn max_blocks() -> Result<u64, CapstoneError> {
+    match std::env::var("MAX_BLOCKS") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|e| CapstoneError::Parse(format!("Invalid MAX_BLOCKS value: {e}"))),
+        Err(_) => Ok(DEFAULT_MAX_BLOCKS),
+    }
+}
+
+/// Fail once mining `next` more blocks would take the run past `cap`
+pub fn check_mining_cap(mined: u64, next: u64, cap: u64) -> Result<(), CapstoneError> {
+    if mined + next > cap {
+        log::warn!("Mining cap reached: {mined} blocks mined, cap is {cap}");
+        return Err(CapstoneError::MiningCapExceeded { mined, next, cap });
+    }
+    Ok(())
+}
+
+/// Mine blocks to `address` until the wallet's spendable balance reaches `target`, reporting each
+/// one to `events`. Returns the number of blocks mined and the final balance.
+pub fn mine_until_spendable(
+    rpc: &Client,
+    address: &str,
+    network: Network,
+    target: Amount,
+    cap: u64,
+    events: &MiningEvents,
+) -> Result<(u64, Amount), CapstoneError> {
+    // Fail before mining anything rather than on the first `generatetoaddress`
+    checked_mining_address(address, network)?;
+
+    check_mining_cap(0, COINBASE_MATURITY + 1, cap)?;
+    let mut blocks_mined = mine_to_maturity(rpc, address, events)?;
+    let balance = rpc.get_balance(None, None)?;
+    if balance >= target {
+        return Ok((blocks_mined, balance));
+    }
+
+    // Each further block matures one more coinbase; only needed when the target exceeds what has matured
+    blocks_mined += mine_until_target_polled(rpc, address, target, blocks_mined, cap, events)?;
+    let balance = rpc.get_balance(None, None)?;
+    log::info!(
+        "Miner wallet balance after {} blocks: {} BTC",
+        blocks_mined,
+        balance.to_btc()
+    );
+    Ok((blocks_mined, balance))
+}
+
+/// Mine chunks of `MINING_CHUNK_BLOCKS` while a second thread polls `getbalances`, stopping as soon
+/// as the poller sees `target`. `already_mined` counts toward `cap`. Returns the blocks mined here.
+fn mine_until_target_polled(
+    rpc: &Client,
+    address: &str,
+    target: Amount,
+    already_mined: u64,
+    cap: u64,
+    events: &MiningEvents,
+) -> Result<u64, CapstoneError> {
+    // Set by the poller when it is done, whether because the target was met or it failed
+    let stop = AtomicBool::new(false);
+    let started = Instant::now();
+
+    std::thread::scope(|scope| {
+        let poller = scope.spawn(|| {
+            let result: bitcoincore_rpc::Result<bool> = (|| loop {
+                if rpc.get_balances()?.mine.trusted >= target {
+                    return Ok(true);
+                }
+                if stop.load(Ordering::Relaxed) || started.elapsed() > TOP_UP_TIMEOUT {
+                    return Ok(false);
+                }
+                std::thread::sleep(BALANCE_POLL_INTERVAL);
+            })();
+            stop.store(true, Ordering::Relaxed);
+            result
+        });
+
+        let mut mined = 0;
+        let mining = (|| -> Result<(), CapstoneError> {
+            while !stop.load(Ordering::Relaxed) {
+                let chunk = MINING_CHUNK_BLOCKS.min(cap.saturating_sub(already_mined + mined));
+                check_mining_cap(already_mined + mined, chunk.max(1), cap)?;
+                if started.elapsed() > TOP_UP_TIMEOUT {
+                    return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                        "Balance did not reach {} BTC within {TOP_UP_TIMEOUT:?}",
+                        target.to_btc()
+                    ))
+                    .into());
+                }
+                let block_hashes =
+                    rpc.call::<Vec<String>>("generatetoaddress", &[json!(chunk), json!(address)])?;
+                events.blocks_mined(rpc, &block_hashes, address)?;
+                mined += chunk;
+                log::debug!(
+                    "Mined {chunk} more blocks ({} in total)",
+                    already_mined + mined
+                );
+            }
+            Ok(())
+        })();
+        // Wake the poller even if mining failed, so the scope can finish
+        stop.store(true, Ordering::Relaxed);
+
+        let reached = poller.join().unwrap_or_else(|_| {
+            Err(bitcoincore_rpc::Error::ReturnedError(
+                "Balance polling thread panicked".to_string(),
+            ))
+        });
+        mining?;
+        if !reached? {
+            return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                "Balance did not reach {} BTC within {TOP_UP_TIMEOUT:?}",
+                target.to_btc()
+            ))
+            .into());
+        }
+        Ok(mined)
+    })
+}
+
+/// Parse `address` as a mining address, failing with `InvalidMiningAddress` if it is malformed or
+/// belongs to a network other than `network`
+pub fn checked_mining_address(address: &str, network: Network) -> Result<Address, CapstoneError> {
+    checked_address(address, network).map_err(|e| CapstoneError::InvalidMiningAddress {
+        address: address.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+/// Address a coinbase descriptor pays to, checked to belong to `network` before anything is mined.
+/// Ranged descriptors (`.../*`) use their first derived address.
+pub fn descriptor_address(
+    rpc: &Client,
+    descriptor: &str,
+    network: Network,
+) -> Result<String, CapstoneError> {
+    #[derive(Deserialize)]
+    struct DescriptorInfo {
+        descriptor: String,
+        isrange: bool,
+    }
+    let invalid = |reason: String| CapstoneError::InvalidMiningAddress {
+        address: descriptor.to_string(),
+        reason,
+    };
+
+    // `getdescriptorinfo` adds the checksum `deriveaddresses` insists on
+    let info = rpc
+        .call::<DescriptorInfo>("getdescriptorinfo", &[json!(descriptor)])
+        .map_err(|e| invalid(e.to_string()))?;
+    let mut params = vec![json!(info.descriptor)];
+    if info.isrange {
+        params.push(json!([0, 0]));
+    }
+    let addresses = rpc
+        .call::<Vec<String>>("deriveaddresses", &params)
+        .map_err(|e| invalid(e.to_string()))?;
+
+    // Multi-path descriptors can derive several addresses; all of them must suit the network
+    let address = addresses
+        .first()
+        .cloned()
+        .ok_or_else(|| invalid("it derives no address".to_string()))?;
+    for derived in &addresses {
+        checked_mining_address(derived, network)?;
+    }
+    Ok(address)
+}
+
+/// Mine `COINBASE_MATURITY + 1` blocks to `address` in one call, so the first of their coinbases
+/// becomes spendable. Returns the number of blocks mined.
+pub fn mine_to_maturity(
+    rpc: &Client,
+    address: &str,
+    events: &MiningEvents,
+) -> Result<u64, CapstoneError> {
+    // Coinbase rewards require `COINBASE_MATURITY` confirmations before becoming spendable to prevent issues from chain reorganizations.
+    // This is why we need to mine that many blocks on top of the first before the miner balance is greater than 0.
+    check_coinbase_maturity_rule(rpc)?;
+    let blocks = COINBASE_MATURITY + 1;
+    let block_hashes =
+        rpc.call::<Vec<String>>("generatetoaddress", &[json!(blocks), json!(address)])?;
+    events.blocks_mined(rpc, &block_hashes, address)?;
+    log::info!(
+        "Mined {} blocks, up to {}",
+        block_hashes.len(),
+        block_hashes.last().map_or("<none>", String::as_str)
+    );
+
+    let balance = rpc.get_balance(None, None)?;
+    log::info!(
+        "Miner wallet balance after {blocks} blocks: {} BTC",
+        balance.to_btc()
+    );
+    if balance <= Amount::ZERO {
+        return Err(CapstoneError::Verification(format!(
+            "Miner balance is still zero after mining {blocks} blocks; the chain may need more than {COINBASE_MATURITY} confirmations for coinbase maturity"
+        )));
+    }
+
+    // Exactly the first coinbase should have matured; less means the maturity rule is stricter than assumed
+    if let Some(first) = block_hashes.first() {
+        let first = bitcoincore_rpc::bitcoin::BlockHash::from_str(first)
+            .map_err(|e| bitcoincore_rpc::Error::ReturnedError(e.to_string()))?;
+        let height = rpc.get_block_header_info(&first)?.height as u64;
+        let matured = rpc.get_balances()?.mine.trusted;
+        let expected = block_subsidy(height, REGTEST_HALVING_INTERVAL);
+        if matured < expected {
+            log::warn!(
+                "Only {} BTC matured after {blocks} blocks, expected the {} BTC coinbase at height {height}; COINBASE_MATURITY ({COINBASE_MATURITY}) may not match this chain",
+                matured.to_btc(),
+                expected.to_btc()
+            );
+        }
+    }
+    Ok(blocks)
+}
+
+/// Warn when the node runs a chain not known to use `COINBASE_MATURITY`, since Core exposes no RPC
+/// reporting the maturity rule itself
+pub fn check_coinbase_maturity_rule(rpc: &Client) -> bitcoincore_rpc::Result<()> {
+    #[derive(Deserialize)]
+    struct ChainName {
+        chain: String,
+    }
+    let chain = rpc.call::<ChainName>("getblockchaininfo", &[])?.chain;
+    if !MATURITY_CHAINS.contains(&chain.as_str()) {
+        log::warn!(
+            "Chain '{chain}' is not known to use a coinbase maturity of {COINBASE_MATURITY} blocks"
+        );
+    }
+    Ok(())
+}
+
+/// Block subsidy paid to the coinbase at `height`, halving every `halving_interval` blocks
+pub fn block_subsidy(height: u64, halving_interval: u64) -> Amount {
+    let halvings = height / halving_interval;
+    // After 64 halvings the right shift would overflow; the subsidy is zero long before that anyway
+    if halvings >= 64 {
+        return Amount::ZERO;
+    }
+    Amount::from_sat(INITIAL_BLOCK_SUBSIDY.to_sat() >> halvings)
+}
+
+/// Total block subsidy earned by mining every block in `first_height..=last_height`
+pub fn total_subsidy(first_height: u64, last_height: u64, halving_interval: u64) -> Amount {
+    (first_height..=last_height)
+        .map(|height| block_subsidy(height, halving_interval))
+        .sum()
+}
+
+/// Confirm a mempool transaction with `generateblock`, so the block holds exactly the coinbase plus our tx.
+/// Returns the hash of the new block.
+pub fn confirm_with_generateblock(
+    rpc: &Client,
+    address: &str,
+    txid: &bitcoincore_rpc::bitcoin::Txid,
+) -> Result<String, CapstoneError> {
+    // Passing the raw hex (rather than the txid) keeps the block contents fully explicit
+    let raw_tx = rpc.get_raw_transaction_hex(txid, None)?;
+
+    #[derive(Deserialize)]
+    struct GenerateBlockResult {
+        hash: String,
+    }
+    let result =
+        rpc.call::<GenerateBlockResult>("generateblock", &[json!(address), json!([raw_tx])])?;
+
+    let block_hash = bitcoincore_rpc::bitcoin::BlockHash::from_str(&result.hash)
+        .map_err(|e| bitcoincore_rpc::Error::ReturnedError(e.to_string()))?;
+    let block = rpc.get_block_info(&block_hash)?;
+    verify_block_composition(&block_hash, &block.tx, txid)?;
+    Ok(result.hash)
+}
+
+/// Value paid out by the coinbase of each block in `block_hashes`
+pub fn coinbase_value(rpc: &Client, block_hashes: &[String]) -> bitcoincore_rpc::Result<Amount> {
+    let mut total = Amount::ZERO;
+    for hash in block_hashes {
+        let hash = bitcoincore_rpc::bitcoin::BlockHash::from_str(hash)
+            .map_err(|e| bitcoincore_rpc::Error::ReturnedError(e.to_string()))?;
+        let block = rpc.get_block(&hash)?;
+        if let Some(coinbase) = block.txdata.first() {
+            total += coinbase.output.iter().map(|out| out.value).sum::<Amount>();
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_mining_address_rejects_malformed_addresses() {
+        assert!(matches!(
+            checked_mining_address("not-an-address", Network::Regtest),
+            Err(CapstoneError::InvalidMiningAddress { address, .. }) if address == "not-an-address"
+        ));
+    }
+
+    #[test]
+    fn checked_mining_address_rejects_other_networks() {
+        let mainnet = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+        assert!(matches!(
+            checked_mining_address(mainnet, Network::Regtest),
+            Err(CapstoneError::InvalidMiningAddress { .. })
+        ));
+        assert!(checked_mining_address(mainnet, Network::Bitcoin).is_ok());
+    }
+}
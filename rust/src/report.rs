@@ -0,0 +1,910 @@
+//! The out.txt report: its fields, the layouts it can be written in, and reading it back; plus the
+//! JSON report, checkpoint and fee history built from it
+
+use crate::wallet::address_type;
+use crate::CapstoneError;
+use bitcoincore_rpc::bitcoin::Amount;
+use bitcoincore_rpc::json::AddressType;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Parse a BTC amount string, rejecting values finer than one satoshi instead of truncating them
+pub fn parse_btc_exact(value: &str) -> Result<Amount, CapstoneError> {
+    use bitcoincore_rpc::bitcoin::amount::ParseAmountError;
+    Amount::from_str_in(value, bitcoincore_rpc::bitcoin::Denomination::Bitcoin).map_err(|e| match e
+    {
+        ParseAmountError::TooPrecise => CapstoneError::SubSatoshi {
+            value: value.to_string(),
+        },
+        other => CapstoneError::Parse(format!("Invalid amount {value} BTC: {other}")),
+    })
+}
+
+/// Convert a BTC value from a JSON result into an exact `Amount`, rejecting sub-satoshi precision
+pub fn btc_value_exact(value: f64) -> Result<Amount, CapstoneError> {
+    // f64's Display is the shortest representation that round-trips, so no digits are invented here
+    parse_btc_exact(&value.to_string())
+}
+
+/// Sum `amounts` in satoshis, failing rather than wrapping if the total overflows
+fn checked_total(amounts: &[Amount], what: &str) -> Result<Amount, CapstoneError> {
+    amounts
+        .iter()
+        .try_fold(Amount::ZERO, |total, &amount| total.checked_add(amount))
+        .ok_or_else(|| CapstoneError::Verification(format!("Sum of {what} overflows")))
+}
+
+/// Fee implied by a transaction's input and output values, failing rather than wrapping if the
+/// sums overflow or the outputs exceed the inputs
+pub fn fee_from_amounts(inputs: &[Amount], outputs: &[Amount]) -> Result<Amount, CapstoneError> {
+    let total_in = checked_total(inputs, "inputs")?;
+    let total_out = checked_total(outputs, "outputs")?;
+    total_in.checked_sub(total_out).ok_or_else(|| {
+        CapstoneError::Verification(format!(
+            "Outputs ({} sat) exceed inputs ({} sat)",
+            total_out.to_sat(),
+            total_in.to_sat()
+        ))
+    })
+}
+
+/// Check a transaction conserves value exactly: its outputs plus `fee` add up to its inputs, to the satoshi
+pub fn verify_amount_conservation(
+    inputs: &[Amount],
+    outputs: &[Amount],
+    fee: Amount,
+) -> Result<(), CapstoneError> {
+    let total_in = checked_total(inputs, "inputs")?;
+    let total_out = checked_total(outputs, "outputs")?;
+    let spent = total_out
+        .checked_add(fee)
+        .ok_or_else(|| CapstoneError::Verification("Outputs plus fee overflow".to_string()))?;
+    if spent != total_in {
+        return Err(CapstoneError::Verification(format!(
+            "Outputs ({} sat) plus fee ({} sat) come to {} sat, but the inputs hold {} sat",
+            total_out.to_sat(),
+            fee.to_sat(),
+            spent.to_sat(),
+            total_in.to_sat()
+        )));
+    }
+    Ok(())
+}
+
+/// Line layouts of out.txt expected by different grader versions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReportLayout {
+    /// All ten fields in the order documented in the README
+    #[default]
+    V1,
+    /// Like `V1` but without the Miner's input amount line
+    V2,
+}
+
+impl ReportLayout {
+    /// Which of `OutReport::fields` appear in the file, in order
+    pub fn field_indices(self) -> &'static [usize] {
+        match self {
+            ReportLayout::V1 => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+            ReportLayout::V2 => &[0, 1, 3, 4, 5, 6, 7, 8, 9],
+        }
+    }
+}
+
+/// How out.txt is written: the line layout, whether the last line ends in a newline, and whether
+/// lines end in CRLF for Windows graders rather than LF
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReportSchema {
+    pub layout: ReportLayout,
+    pub final_newline: bool,
+    pub crlf: bool,
+}
+
+impl Default for ReportSchema {
+    fn default() -> Self {
+        ReportSchema {
+            layout: ReportLayout::default(),
+            final_newline: true,
+            crlf: false,
+        }
+    }
+}
+
+impl ReportSchema {
+    /// The line ending this schema writes
+    pub fn line_ending(&self) -> &'static str {
+        if self.crlf {
+            "\r\n"
+        } else {
+            "\n"
+        }
+    }
+}
+
+impl FromStr for ReportSchema {
+    type Err = String;
+
+    /// `v1` or `v2`, optionally suffixed with `-noeol` for graders that reject a trailing newline
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, final_newline) = match s.strip_suffix("-noeol") {
+            Some(name) => (name, false),
+            None => (s, true),
+        };
+        let layout = match name {
+            "v1" => ReportLayout::V1,
+            "v2" => ReportLayout::V2,
+            _ => {
+                return Err(format!(
+                    "unknown report schema '{s}' (expected v1 or v2, optionally with -noeol)"
+                ))
+            }
+        };
+        Ok(ReportSchema {
+            layout,
+            final_newline,
+            crlf: false,
+        })
+    }
+}
+
+/// The transaction details written to out.txt, one field per line
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct OutReport {
+    pub txid: String,
+    pub miner_input_address: String,
+    #[serde(serialize_with = "serialize_btc")]
+    pub miner_input_amount: String,
+    pub trader_output_address: String,
+    #[serde(serialize_with = "serialize_btc")]
+    pub trader_output_amount: String,
+    pub miner_change_address: String,
+    #[serde(serialize_with = "serialize_btc")]
+    pub miner_change_amount: String,
+    #[serde(serialize_with = "serialize_btc")]
+    pub transaction_fees: String,
+    pub block_height: String,
+    pub block_hash: String,
+}
+
+/// Serialize a BTC amount string with exactly 8 decimal places, going through satoshis so no
+/// float rounding creeps in
+fn serialize_btc<S: serde::Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    let amount = bitcoincore_rpc::bitcoin::SignedAmount::from_str_in(
+        value,
+        bitcoincore_rpc::bitcoin::Denomination::Bitcoin,
+    )
+    .map_err(serde::ser::Error::custom)?;
+    serializer.serialize_str(&btc_string(amount))
+}
+
+/// Serialize an `Amount` the way `serialize_btc` writes amount strings: exactly 8 decimal places
+pub fn serialize_amount<S: serde::Serializer>(
+    amount: &Amount,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let amount = amount.to_signed().map_err(serde::ser::Error::custom)?;
+    serializer.serialize_str(&btc_string(amount))
+}
+
+/// `amount` in BTC with exactly 8 decimal places
+fn btc_string(amount: bitcoincore_rpc::bitcoin::SignedAmount) -> String {
+    let sats = amount.to_sat().unsigned_abs();
+    let sign = if amount.is_negative() { "-" } else { "" };
+    format!("{sign}{}.{:08}", sats / 100_000_000, sats % 100_000_000)
+}
+
+/// One recipient output of the payment, as listed in the JSON report
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RecipientOutput {
+    pub address: String,
+    #[serde(serialize_with = "serialize_amount")]
+    pub amount: Amount,
+}
+
+/// How out.txt is encoded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// One field per line, laid out by a `ReportSchema`
+    #[default]
+    Text,
+    /// A pretty-printed JSON object with named fields
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            other => Err(format!(
+                "unknown report format '{other}' (expected text or json)"
+            )),
+        }
+    }
+}
+
+impl OutReport {
+    /// The fields in the order the grader expects them in out.txt
+    pub fn fields(&self) -> [&str; 10] {
+        [
+            &self.txid,
+            &self.miner_input_address,
+            &self.miner_input_amount,
+            &self.trader_output_address,
+            &self.trader_output_amount,
+            &self.miner_change_address,
+            &self.miner_change_amount,
+            &self.transaction_fees,
+            &self.block_height,
+            &self.block_hash,
+        ]
+    }
+
+    /// Write the report one field per line in the order documented in the README
+    pub fn write_to<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        self.write_with_schema(w, ReportSchema::default())
+    }
+
+    /// Write the report one field per line using the given schema's layout
+    pub fn write_with_schema<W: Write>(
+        &self,
+        w: &mut W,
+        schema: ReportSchema,
+    ) -> std::io::Result<()> {
+        let fields = self.fields();
+        let lines: Vec<&str> = schema
+            .layout
+            .field_indices()
+            .iter()
+            .map(|&index| fields[index])
+            .collect();
+        write!(w, "{}", lines.join(schema.line_ending()))?;
+        if schema.final_newline {
+            write!(w, "{}", schema.line_ending())?;
+        }
+        Ok(())
+    }
+
+    /// Rebuild a report from its fields in out.txt order; `None` unless exactly ten are given
+    pub fn from_fields(fields: &[&str]) -> Option<Self> {
+        if fields.len() != 10 {
+            return None;
+        }
+        let field = |i: usize| fields[i].to_string();
+        Some(OutReport {
+            txid: field(0),
+            miner_input_address: field(1),
+            miner_input_amount: field(2),
+            trader_output_address: field(3),
+            trader_output_amount: field(4),
+            miner_change_address: field(5),
+            miner_change_amount: field(6),
+            transaction_fees: field(7),
+            block_height: field(8),
+            block_hash: field(9),
+        })
+    }
+
+    /// The report as it appears in out.txt
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for field in self.fields() {
+            text.push_str(field);
+            text.push('\n');
+        }
+        text
+    }
+}
+
+/// Read a ten-line (v1) out.txt back into an `OutReport`, the inverse of `write_to`
+pub fn parse_report(path: &Path) -> Result<OutReport, CapstoneError> {
+    let contents = std::fs::read_to_string(path)?;
+    let lines: Vec<&str> = contents.lines().map(str::trim).collect();
+    let malformed = |line_no: usize, reason: &str| {
+        CapstoneError::Parse(format!(
+            "Malformed report {} line {line_no} ({:?}): {reason}",
+            path.display(),
+            lines.get(line_no - 1).copied().unwrap_or_default()
+        ))
+    };
+
+    let report = OutReport::from_fields(&lines).ok_or_else(|| {
+        CapstoneError::Parse(format!(
+            "Malformed report {}: expected 10 lines, found {}",
+            path.display(),
+            lines.len()
+        ))
+    })?;
+
+    let is_hash = |s: &str| s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit());
+    if !is_hash(&report.txid) {
+        return Err(malformed(1, "not a txid"));
+    }
+    for (line_no, address) in [
+        (2, &report.miner_input_address),
+        (4, &report.trader_output_address),
+        (6, &report.miner_change_address),
+    ] {
+        if address.is_empty() {
+            return Err(malformed(line_no, "empty address"));
+        }
+    }
+    for (line_no, amount) in [
+        (3, &report.miner_input_amount),
+        (5, &report.trader_output_amount),
+        (7, &report.miner_change_amount),
+    ] {
+        parse_btc_exact(amount).map_err(|e| malformed(line_no, &e.to_string()))?;
+    }
+    // The fee may be written as a negative number (wallet convention), so only check it is numeric
+    if report.transaction_fees.parse::<f64>().is_err() {
+        return Err(malformed(8, "not a number"));
+    }
+    if report.block_height.parse::<u64>().is_err() {
+        return Err(malformed(9, "not a block height"));
+    }
+    if !is_hash(&report.block_hash) {
+        return Err(malformed(10, "not a block hash"));
+    }
+    Ok(report)
+}
+
+/// Render the report with run-specific values (txid, addresses, block hash) replaced by placeholders,
+/// so it can be compared against a golden file across runs
+pub fn canonical_report(report: &OutReport) -> String {
+    let canonical = OutReport {
+        txid: "<TXID>".to_string(),
+        miner_input_address: "<MINER_INPUT_ADDRESS>".to_string(),
+        trader_output_address: "<TRADER_OUTPUT_ADDRESS>".to_string(),
+        miner_change_address: "<MINER_CHANGE_ADDRESS>".to_string(),
+        block_hash: "<BLOCK_HASH>".to_string(),
+        ..report.clone()
+    };
+    canonical.to_text()
+}
+
+/// `txid<TAB>fee<TAB>block_height<TAB>block_hash`, for `awk`/`cut` pipelines
+pub fn oneline_report(report: &OutReport) -> String {
+    [
+        report.txid.as_str(),
+        &report.transaction_fees,
+        &report.block_height,
+        &report.block_hash,
+    ]
+    .join("\t")
+}
+
+// How long to wait for a `--webhook` endpoint before giving up on it
+const WEBHOOK_TIMEOUT_SECS: u64 = 30;
+
+/// Pre-send fee estimate next to what the transaction actually paid, for the JSON report
+#[derive(Debug, Serialize)]
+pub struct FeeComparison {
+    pub estimated_sat_vb: Option<f64>,
+    pub actual_sat_vb: f64,
+    #[serde(serialize_with = "serialize_amount")]
+    pub actual_fee_btc: Amount,
+}
+
+/// Number of inputs and outputs in a decoded transaction
+pub fn io_counts(decoded_tx: &serde_json::Value) -> (usize, usize) {
+    let count = |key: &str| decoded_tx[key].as_array().map_or(0, Vec::len);
+    (count("vin"), count("vout"))
+}
+
+/// A transaction's size metrics and how many bytes the segwit witness discount saved
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SegwitSavings {
+    pub size: u64,
+    pub vsize: u64,
+    pub weight: u64,
+    pub witness_discount: u64,
+}
+
+/// Log the transaction's size metrics and how many bytes the segwit witness discount saved
+pub fn log_segwit_savings(decoded_tx: &serde_json::Value) -> SegwitSavings {
+    let size = decoded_tx["size"].as_u64().unwrap_or(0);
+    let vsize = decoded_tx["vsize"].as_u64().unwrap_or(0);
+    let weight = decoded_tx["weight"].as_u64().unwrap_or(0);
+
+    // Witness bytes count a quarter towards vsize, so the fee is paid on fewer bytes than are serialized
+    let witness_discount = size.saturating_sub(vsize);
+    log::info!("Transaction size: {size} bytes, vsize: {vsize} vbytes, weight: {weight} WU");
+    log::info!("Segwit witness discount: {witness_discount} vbytes saved");
+    SegwitSavings {
+        size,
+        vsize,
+        weight,
+        witness_discount,
+    }
+}
+
+/// Append a confirmed send's report to the checkpoint file as one tab-separated line, led by the
+/// ID of the run that made it
+pub fn append_checkpoint(
+    path: &Path,
+    run_id: &str,
+    report: &OutReport,
+) -> bitcoincore_rpc::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{run_id}\t{}", report.fields().join("\t"))?;
+    Ok(())
+}
+
+/// Append one `timestamp,txid,fee_rate_sat_vb,block_height` row to the fee history at `path`,
+/// writing the header first if the file is new. Each row goes out in a single append so
+/// concurrent runs cannot interleave their lines.
+pub fn append_fee_history(
+    path: &Path,
+    txid: &str,
+    fee_rate: f64,
+    block_height: u64,
+) -> bitcoincore_rpc::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+
+    let mut row = String::new();
+    if file.metadata()?.len() == 0 {
+        row.push_str("timestamp,txid,fee_rate_sat_vb,block_height\n");
+    }
+    row.push_str(&format!(
+        "{timestamp},{txid},{fee_rate:.3},{block_height}\n"
+    ));
+    file.write_all(row.as_bytes())?;
+    Ok(())
+}
+
+/// The `(run ID, report)` entries of a checkpoint file's contents, in the order they were appended
+pub fn parse_checkpoint(contents: &str) -> Result<Vec<(String, OutReport)>, CapstoneError> {
+    let mut entries = Vec::new();
+    for (line_no, line) in contents.lines().enumerate() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let entry = fields.split_first().and_then(|(run_id, fields)| {
+            OutReport::from_fields(fields).map(|report| (run_id.to_string(), report))
+        });
+        entries.push(entry.ok_or_else(|| {
+            CapstoneError::Parse(format!(
+                "Malformed checkpoint line {}: expected a run ID and 10 fields, found {} fields",
+                line_no + 1,
+                fields.len()
+            ))
+        })?);
+    }
+    Ok(entries)
+}
+
+/// Read back the sends recorded in a checkpoint file that are still confirmed. Entries whose
+/// transaction is no longer in the active chain are dropped with a warning, so their runs are redone
+/// rather than skipped. Only needs a node-level client, as the recorded block locates each transaction.
+pub fn load_checkpoint(
+    rpc: &Client,
+    path: &Path,
+) -> Result<Vec<(String, OutReport)>, CapstoneError> {
+    let mut confirmed = Vec::new();
+    for (run_id, report) in parse_checkpoint(&std::fs::read_to_string(path)?)? {
+        match checkpointed_confirmations(rpc, &report) {
+            Ok(confirmations) if confirmations >= 1 => confirmed.push((run_id, report)),
+            Ok(confirmations) => log::warn!(
+                "Checkpointed run {run_id} ({}) has {confirmations} confirmations in the active chain; it will be redone",
+                report.txid
+            ),
+            Err(e) => log::warn!(
+                "Checkpointed run {run_id} ({}) could not be found in block {}: {e}; it will be redone",
+                report.txid,
+                report.block_hash
+            ),
+        }
+    }
+    Ok(confirmed)
+}
+
+/// Confirmations of the checkpointed transaction, looked up in its recorded block; 0 when that
+/// block has left the active chain
+fn checkpointed_confirmations(rpc: &Client, report: &OutReport) -> bitcoincore_rpc::Result<i64> {
+    #[derive(Deserialize)]
+    struct BlockTx {
+        in_active_chain: Option<bool>,
+        confirmations: Option<i64>,
+    }
+    let tx = rpc.call::<BlockTx>(
+        "getrawtransaction",
+        &[json!(report.txid), json!(true), json!(report.block_hash)],
+    )?;
+    if tx.in_active_chain == Some(false) {
+        return Ok(0);
+    }
+    Ok(tx.confirmations.unwrap_or(0))
+}
+
+/// Sign the report text with the key behind `address` so a verifier can check who produced it.
+/// `signmessage` only works with legacy (P2PKH) addresses, so segwit addresses are rejected up front.
+pub fn sign_report(
+    rpc: &Client,
+    address: &str,
+    report: &OutReport,
+) -> bitcoincore_rpc::Result<String> {
+    let kind = address_type(address);
+    if kind != Some(bitcoin::AddressType::P2pkh) {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "Cannot sign the report with {address} ({kind:?}): signmessage requires a legacy P2PKH address"
+        )));
+    }
+    rpc.call::<String>("signmessage", &[json!(address), json!(report.to_text())])
+}
+
+/// Details of a run that have no line in out.txt but go into the JSON report
+pub struct ReportDetails<'a> {
+    pub run_id: &'a str,
+    pub fees: &'a FeeComparison,
+    pub recipients: &'a [(String, Amount)],
+    pub address_type: Option<AddressType>,
+    /// `--sign` signature over the report text, and the address whose key made it
+    pub signature: Option<(String, String)>,
+    pub segwit_savings: &'a SegwitSavings,
+    /// Number of inputs and outputs, from `io_counts`
+    pub io_counts: (usize, usize),
+}
+
+/// The report as JSON, enriched with details that have no line in out.txt
+pub fn report_json(report: &OutReport, details: &ReportDetails) -> serde_json::Value {
+    let mut value = json!(report);
+    value["run_id"] = json!(details.run_id);
+    value["fee_comparison"] = json!(details.fees);
+    // `null` when the wallets used their default address type
+    value["address_type"] = json!(details.address_type);
+    value["recipients"] = details
+        .recipients
+        .iter()
+        .map(|(address, amount)| {
+            json!(RecipientOutput {
+                address: address.clone(),
+                amount: *amount,
+            })
+        })
+        .collect();
+    // Both `null` unless the report was signed
+    let (signature, signing_address) = details.signature.clone().unzip();
+    value["signature"] = json!(signature);
+    value["signing_address"] = json!(signing_address);
+    value["segwit_savings"] = json!(details.segwit_savings);
+    value["vin_count"] = json!(details.io_counts.0);
+    value["vout_count"] = json!(details.io_counts.1);
+    value
+}
+
+/// POST the JSON report to `url`. A failed delivery only warns: out.txt is already written.
+pub fn post_report_webhook(url: &str, report: &serde_json::Value) {
+    let response = minreq::post(url)
+        .with_header("Content-Type", "application/json")
+        .with_body(report.to_string())
+        .with_timeout(WEBHOOK_TIMEOUT_SECS)
+        .send();
+    match response {
+        Ok(response) if (200..300).contains(&response.status_code) => {
+            log::info!("Report posted to {url} ({})", response.status_code);
+        }
+        Ok(response) => log::warn!(
+            "Webhook {url} answered {} {}",
+            response.status_code,
+            response.reason_phrase
+        ),
+        Err(e) => log::warn!("Could not post the report to {url}: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_btc_exact_rejects_sub_satoshi_amounts() {
+        assert!(matches!(
+            parse_btc_exact("0.000000001"),
+            Err(CapstoneError::SubSatoshi { .. })
+        ));
+        assert_eq!(parse_btc_exact("0.00000001").unwrap(), Amount::from_sat(1));
+    }
+
+    #[test]
+    fn btc_value_exact_rejects_sub_satoshi_amounts() {
+        assert!(matches!(
+            btc_value_exact(0.000000001),
+            Err(CapstoneError::SubSatoshi { .. })
+        ));
+        assert_eq!(
+            btc_value_exact(20.0).unwrap(),
+            Amount::from_btc(20.0).unwrap()
+        );
+    }
+
+    const MAX_MONEY: Amount = Amount::from_sat(21_000_000 * 100_000_000);
+
+    #[test]
+    fn amount_conservation_holds_exactly() {
+        let inputs = [Amount::from_btc(50.0).unwrap()];
+        let outputs = [
+            Amount::from_btc(20.0).unwrap(),
+            Amount::from_sat(2_999_999_000),
+        ];
+        let fee = Amount::from_sat(1_000);
+        assert!(verify_amount_conservation(&inputs, &outputs, fee).is_ok());
+        assert_eq!(fee_from_amounts(&inputs, &outputs).unwrap(), fee);
+    }
+
+    #[test]
+    fn amount_conservation_rejects_one_satoshi_either_way() {
+        let inputs = [Amount::from_btc(50.0).unwrap()];
+        let outputs = [
+            Amount::from_btc(20.0).unwrap(),
+            Amount::from_sat(2_999_999_000),
+        ];
+        for fee in [Amount::from_sat(999), Amount::from_sat(1_001)] {
+            assert!(matches!(
+                verify_amount_conservation(&inputs, &outputs, fee),
+                Err(CapstoneError::Verification(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn amount_conservation_rejects_overflowing_sums() {
+        let near_max = [MAX_MONEY, MAX_MONEY];
+        assert!(verify_amount_conservation(&near_max, &[MAX_MONEY], MAX_MONEY).is_ok());
+
+        let overflowing = [Amount::MAX, Amount::from_sat(1)];
+        assert!(matches!(
+            verify_amount_conservation(&overflowing, &[], Amount::ZERO),
+            Err(CapstoneError::Verification(message)) if message.contains("inputs overflows")
+        ));
+        assert!(matches!(
+            verify_amount_conservation(&[], &overflowing, Amount::ZERO),
+            Err(CapstoneError::Verification(message)) if message.contains("outputs overflows")
+        ));
+        assert!(fee_from_amounts(&overflowing, &[]).is_err());
+    }
+
+    #[test]
+    fn amount_conservation_rejects_outputs_plus_fee_overflow() {
+        assert!(matches!(
+            verify_amount_conservation(&[Amount::MAX], &[Amount::MAX], Amount::from_sat(1)),
+            Err(CapstoneError::Verification(message)) if message.contains("Outputs plus fee overflow")
+        ));
+    }
+
+    #[test]
+    fn amount_conservation_with_no_inputs() {
+        assert!(verify_amount_conservation(&[], &[], Amount::ZERO).is_ok());
+        assert!(verify_amount_conservation(&[], &[Amount::from_sat(1)], Amount::ZERO).is_err());
+        assert!(verify_amount_conservation(&[], &[], Amount::from_sat(1)).is_err());
+        assert!(matches!(
+            fee_from_amounts(&[], &[Amount::from_sat(1)]),
+            Err(CapstoneError::Verification(_))
+        ));
+    }
+
+    #[test]
+    fn recipient_amounts_serialize_with_eight_decimals() {
+        let recipient = RecipientOutput {
+            address: "bcrt1qtrader".to_string(),
+            amount: Amount::from_sat(2_000_000_001),
+        };
+        assert_eq!(
+            serde_json::to_value(&recipient).unwrap(),
+            serde_json::json!({ "address": "bcrt1qtrader", "amount": "20.00000001" })
+        );
+    }
+
+    fn sample_report() -> OutReport {
+        OutReport {
+            txid: "a".repeat(64),
+            miner_input_address: "bcrt1qminerinput".to_string(),
+            miner_input_amount: "50.00000000".to_string(),
+            trader_output_address: "bcrt1qtrader".to_string(),
+            trader_output_amount: "20.00000000".to_string(),
+            miner_change_address: "bcrt1qminerchange".to_string(),
+            miner_change_amount: "29.99999000".to_string(),
+            transaction_fees: "-0.00001000".to_string(),
+            block_height: "102".to_string(),
+            block_hash: "b".repeat(64),
+        }
+    }
+
+    /// Write `contents` to a file unique to `name` under the temp directory
+    fn temp_report(name: &str, contents: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("capstone-report-{}-{name}.txt", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn parse_report_round_trips_write_to() {
+        let report = sample_report();
+        let mut written = Vec::new();
+        report.write_to(&mut written).unwrap();
+        let path = temp_report("round-trip", &String::from_utf8(written).unwrap());
+        assert_eq!(parse_report(&path).unwrap(), report);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn parse_report_rejects_each_malformed_line() {
+        let cases: [(usize, &str); 10] = [
+            (1, "not-a-txid"),
+            (2, ""),
+            (3, "fifty"),
+            (4, ""),
+            (5, "0.000000001"),
+            (6, ""),
+            (7, "-"),
+            (8, "fee"),
+            (9, "-1"),
+            (10, "not-a-hash"),
+        ];
+        for (line_no, bad) in cases {
+            let report = sample_report();
+            let mut fields = report.fields().map(str::to_string);
+            fields[line_no - 1] = bad.to_string();
+            let contents = fields.join("\n");
+            let path = temp_report(&format!("line-{line_no}"), &contents);
+            let err = parse_report(&path).unwrap_err();
+            std::fs::remove_file(path).unwrap();
+            match err {
+                CapstoneError::Parse(message) => assert!(
+                    message.contains(&format!("line {line_no} ")),
+                    "line {line_no}: {message}"
+                ),
+                other => panic!("line {line_no}: expected a Parse error, got {other}"),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_report_rejects_a_short_report() {
+        let path = temp_report("short", "only\nthree\nlines\n");
+        let err = parse_report(&path).unwrap_err();
+        std::fs::remove_file(path).unwrap();
+        assert!(
+            matches!(err, CapstoneError::Parse(message) if message.contains("expected 10 lines"))
+        );
+    }
+
+    #[test]
+    fn parse_btc_exact_reports_other_errors_as_parse() {
+        assert!(matches!(
+            parse_btc_exact("abc"),
+            Err(CapstoneError::Parse(_))
+        ));
+    }
+
+    fn sample_fees() -> FeeComparison {
+        FeeComparison {
+            estimated_sat_vb: None,
+            actual_sat_vb: 10.0,
+            actual_fee_btc: Amount::from_sat(1_410),
+        }
+    }
+
+    /// Details for run 1 of an unsigned single-input, two-output send with no recipients listed
+    fn sample_details<'a>(
+        fees: &'a FeeComparison,
+        segwit_savings: &'a SegwitSavings,
+    ) -> ReportDetails<'a> {
+        ReportDetails {
+            run_id: "1",
+            fees,
+            recipients: &[],
+            address_type: None,
+            signature: None,
+            segwit_savings,
+            io_counts: (1, 2),
+        }
+    }
+
+    #[test]
+    fn report_json_records_the_signature_and_signing_address() {
+        let report = sample_report();
+        let fees = sample_fees();
+        let recipients = [(
+            "bcrt1qtrader".to_string(),
+            Amount::from_sat(20 * 100_000_000),
+        )];
+        let segwit_savings = SegwitSavings::default();
+        let mut details = ReportDetails {
+            run_id: "7",
+            recipients: &recipients,
+            ..sample_details(&fees, &segwit_savings)
+        };
+        let unsigned = report_json(&report, &details);
+        assert_eq!(unsigned["run_id"], "7");
+        assert!(unsigned["signature"].is_null());
+        assert!(unsigned["signing_address"].is_null());
+
+        details.signature = Some(("H3sig==".to_string(), "mminer".to_string()));
+        let signed = report_json(&report, &details);
+        assert_eq!(signed["signature"], "H3sig==");
+        assert_eq!(signed["signing_address"], "mminer");
+    }
+
+    #[test]
+    fn segwit_savings_are_returned_for_the_report() {
+        let decoded = json!({ "size": 222, "vsize": 141, "weight": 561 });
+        assert_eq!(
+            log_segwit_savings(&decoded),
+            SegwitSavings {
+                size: 222,
+                vsize: 141,
+                weight: 561,
+                witness_discount: 81,
+            }
+        );
+        let fees = sample_fees();
+        let segwit_savings = log_segwit_savings(&decoded);
+        let value = report_json(&sample_report(), &sample_details(&fees, &segwit_savings));
+        assert_eq!(value["segwit_savings"]["witness_discount"], 81);
+    }
+
+    #[test]
+    fn io_counts_go_into_the_report() {
+        let decoded = json!({ "vin": [{}], "vout": [{}, {}, {}] });
+        assert_eq!(io_counts(&decoded), (1, 3));
+        assert_eq!(io_counts(&json!({})), (0, 0));
+
+        let fees = sample_fees();
+        let segwit_savings = SegwitSavings::default();
+        let value = report_json(
+            &sample_report(),
+            &ReportDetails {
+                io_counts: io_counts(&decoded),
+                ..sample_details(&fees, &segwit_savings)
+            },
+        );
+        assert_eq!(value["vin_count"], 1);
+        assert_eq!(value["vout_count"], 3);
+    }
+
+    #[test]
+    fn checkpoint_entries_round_trip_with_their_run_id() {
+        let path = std::env::temp_dir().join(format!("capstone-checkpoint-{}", std::process::id()));
+        let report = sample_report();
+        append_checkpoint(&path, "3", &report).unwrap();
+        append_checkpoint(&path, "4", &report).unwrap();
+        let entries = parse_checkpoint(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            entries,
+            [("3".to_string(), report.clone()), ("4".to_string(), report)]
+        );
+    }
+
+    #[test]
+    fn checkpoint_lines_without_a_run_id_are_rejected() {
+        let legacy_line = sample_report().fields().join("\t");
+        assert!(matches!(
+            parse_checkpoint(&legacy_line),
+            Err(CapstoneError::Parse(message)) if message.contains("line 1")
+        ));
+    }
+
+    #[test]
+    fn fee_comparison_writes_the_fee_with_eight_decimals() {
+        assert_eq!(
+            json!(sample_fees()),
+            json!({ "estimated_sat_vb": null, "actual_sat_vb": 10.0, "actual_fee_btc": "0.00001410" })
+        );
+    }
+}
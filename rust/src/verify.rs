@@ -0,0 +1,568 @@
+//! Checks that the send, its confirmation and the written report match what the chain holds
+
+use crate::report::{btc_value_exact, parse_btc_exact, OutReport, ReportSchema};
+use crate::wallet::{address_type, is_mine};
+use crate::CapstoneError;
+use bitcoincore_rpc::bitcoin::Amount;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Check the output classified as the recipient pays the address we actually sent to
+pub fn verify_recipient_address(
+    matched: Option<&str>,
+    requested: &str,
+) -> Result<(), CapstoneError> {
+    match matched {
+        Some(address) if address == requested => Ok(()),
+        Some(address) => Err(CapstoneError::Verification(format!(
+            "Recipient output pays {address}, but the payment was sent to {requested}"
+        ))),
+        None => Err(CapstoneError::Verification(format!(
+            "No recipient output found for {requested}"
+        ))),
+    }
+}
+
+/// Check a single payment's recipient output carries exactly the configured amount, to the satoshi.
+/// Anything less means the fee was taken out of the payment rather than the change.
+pub fn verify_send_amount(paid: Amount, configured: Amount) -> Result<(), CapstoneError> {
+    if paid != configured {
+        return Err(CapstoneError::Verification(format!(
+            "Recipient output pays {} sat, but {} sat was configured to be sent",
+            paid.to_sat(),
+            configured.to_sat()
+        )));
+    }
+    Ok(())
+}
+
+/// The recipient and change outputs of a two-output send must pay different addresses; a match
+/// means change went to the Trader or the outputs were misclassified
+pub fn verify_distinct_outputs(
+    trader_output_address: &str,
+    miner_change_address: &str,
+) -> Result<(), CapstoneError> {
+    if trader_output_address == miner_change_address {
+        return Err(CapstoneError::Verification(format!(
+            "Trader output and Miner change both pay {trader_output_address}"
+        )));
+    }
+    Ok(())
+}
+
+/// Every address generated during the run must be distinct; a duplicate would make output
+/// classification ambiguous
+pub fn verify_unique_addresses<'a>(
+    addresses: impl IntoIterator<Item = &'a str>,
+) -> Result<(), CapstoneError> {
+    let mut seen = HashSet::new();
+    for address in addresses {
+        if !seen.insert(address) {
+            return Err(CapstoneError::Verification(format!(
+                "Address {address} was generated more than once in this run"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Verify the change output pays back to a Miner-owned address of the same script type as the funding address
+pub fn verify_change_output(
+    miner_rpc: &Client,
+    change_address: &str,
+    funding_address: &str,
+) -> Result<(), CapstoneError> {
+    if !is_mine(miner_rpc, change_address)? {
+        return Err(CapstoneError::Verification(format!(
+            "Change address {change_address} is not owned by the Miner wallet"
+        )));
+    }
+
+    // A differing type usually means the node's `change_type` setting is not what we expect
+    let change_type = address_type(change_address);
+    let funding_type = address_type(funding_address);
+    if change_type != funding_type {
+        log::warn!(
+            "Change address type {change_type:?} differs from funding address type {funding_type:?}"
+        );
+    }
+    Ok(())
+}
+
+/// Wallet confirmation count of a transaction (0 in the mempool, negative if conflicted)
+pub fn tx_confirmations(rpc: &Client, txid: &str) -> bitcoincore_rpc::Result<i64> {
+    #[derive(Deserialize)]
+    struct TxConfirmations {
+        confirmations: i64,
+    }
+    Ok(rpc
+        .call::<TxConfirmations>("gettransaction", &[json!(txid)])?
+        .confirmations)
+}
+
+/// Whether the wallet transaction `txid` has at least `min_confs` confirmations. A conflicted
+/// transaction reports negative confirmations and never counts as confirmed.
+pub fn verify_confirmation(
+    rpc: &Client,
+    txid: &bitcoincore_rpc::bitcoin::Txid,
+    min_confs: u32,
+) -> bitcoincore_rpc::Result<bool> {
+    let confirmations = rpc.get_transaction(txid, None)?.info.confirmations;
+    if confirmations < 0 {
+        log::warn!("{txid} conflicts with the chain ({confirmations} confirmations)");
+        return Ok(false);
+    }
+    Ok(confirmations as u32 >= min_confs)
+}
+
+/// Check mining `blocks_mined` blocks raised the tx's confirmations by exactly that much; a smaller
+/// increase means a reorg replaced the confirming block
+pub fn verify_confirmation_increase(
+    rpc: &Client,
+    txid: &str,
+    before: i64,
+    blocks_mined: usize,
+) -> Result<(), CapstoneError> {
+    let expected = before + blocks_mined as i64;
+    let actual = tx_confirmations(rpc, txid)?;
+    if actual != expected {
+        return Err(CapstoneError::Verification(format!(
+            "Transaction {txid} has {actual} confirmations after mining {blocks_mined} blocks, expected {expected}"
+        )));
+    }
+    Ok(())
+}
+
+/// A block built from an explicit tx list must hold exactly the coinbase and `txid`, in that order
+pub fn verify_block_composition(
+    block_hash: &bitcoincore_rpc::bitcoin::BlockHash,
+    txids: &[bitcoincore_rpc::bitcoin::Txid],
+    txid: &bitcoincore_rpc::bitcoin::Txid,
+) -> Result<(), CapstoneError> {
+    if txids.len() != 2 || txids[1] != *txid {
+        let contents: Vec<String> = txids.iter().map(ToString::to_string).collect();
+        return Err(CapstoneError::Verification(format!(
+            "Block {block_hash} should hold the coinbase and {txid} at index 1, but holds {} transactions: [{}]",
+            txids.len(),
+            contents.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Fail unless the Trader gained exactly `sent` and the Miner lost exactly `sent + fee`, less the
+/// `minted` coinbase of the confirmation block it mined. Balances are totals, immature coins included.
+pub fn verify_scenario_balances(
+    (miner_before, miner_after): (Amount, Amount),
+    (trader_before, trader_after): (Amount, Amount),
+    sent: Amount,
+    fee: Amount,
+    minted: Amount,
+) -> Result<(), CapstoneError> {
+    let sats = |amount: Amount| amount.to_sat() as i64;
+    let btc = |sats: i64| bitcoincore_rpc::bitcoin::SignedAmount::from_sat(sats).to_btc();
+
+    let trader_gain = sats(trader_after) - sats(trader_before);
+    if trader_gain != sats(sent) {
+        return Err(CapstoneError::Verification(format!(
+            "Trader balance went from {} to {} BTC, a change of {:+} BTC, but {} BTC was sent",
+            trader_before.to_btc(),
+            trader_after.to_btc(),
+            btc(trader_gain),
+            sent.to_btc()
+        )));
+    }
+
+    let miner_loss = sats(miner_before) - sats(miner_after);
+    let expected_loss = sats(sent) + sats(fee) - sats(minted);
+    if miner_loss != expected_loss {
+        return Err(CapstoneError::Verification(format!(
+            "Miner balance fell by {} BTC, expected {} BTC sent + {} BTC fee - {} BTC confirmation coinbase = {} BTC",
+            btc(miner_loss),
+            sent.to_btc(),
+            fee.to_btc(),
+            minted.to_btc(),
+            btc(expected_loss)
+        )));
+    }
+    Ok(())
+}
+
+/// After sending to itself a wallet should be poorer by exactly the fee: the payment never left it
+pub fn verify_self_send_balance(
+    before: Amount,
+    after: Amount,
+    fee: Amount,
+) -> Result<(), CapstoneError> {
+    log::info!(
+        "Self-send: wallet balance {} -> {} BTC, fee {} BTC",
+        before.to_btc(),
+        after.to_btc(),
+        fee.to_btc()
+    );
+    if before.checked_sub(after) != Some(fee) {
+        return Err(CapstoneError::Verification(format!(
+            "Self-send changed the wallet balance by {} BTC, expected exactly the {} BTC fee",
+            before.to_btc() - after.to_btc(),
+            fee.to_btc()
+        )));
+    }
+    Ok(())
+}
+
+/// Re-check, once out.txt is written, that the chain ending at `getbestblockhash` still contains the
+/// recorded block, so a reorg between reading the block hash and writing the file cannot go unnoticed
+pub fn verify_block_still_in_chain(rpc: &Client, block_hash: &str) -> Result<(), CapstoneError> {
+    let block_hash = bitcoincore_rpc::bitcoin::BlockHash::from_str(block_hash)
+        .map_err(|e| CapstoneError::Parse(format!("Invalid block hash '{block_hash}': {e}")))?;
+    let best_block_hash = rpc.get_best_block_hash()?;
+    let header = rpc.get_block_header_info(&block_hash)?;
+    if header.confirmations <= 0 {
+        return Err(CapstoneError::Verification(format!(
+            "Block {block_hash} left the chain ending at {best_block_hash} before out.txt was finalized (confirmations: {})",
+            header.confirmations
+        )));
+    }
+    Ok(())
+}
+
+/// Recompute the block's Merkle root from its transactions, check it matches the header's
+/// `merkleroot`, and check `txid` is one of the committed transactions
+pub fn verify_merkle_root(
+    rpc: &Client,
+    block_hash: &bitcoincore_rpc::bitcoin::BlockHash,
+    txid: &bitcoincore_rpc::bitcoin::Txid,
+) -> Result<(), CapstoneError> {
+    let header = rpc.get_block_header_info(block_hash)?;
+    let block = rpc
+        .get_block(block_hash)
+        .map_err(|e| pruned_block_error(e, &block_hash.to_string()))?;
+
+    let computed = block.compute_merkle_root();
+    if computed != Some(header.merkle_root) {
+        return Err(CapstoneError::Verification(format!(
+            "Block {block_hash} header commits to Merkle root {}, but its transactions hash to {computed:?}",
+            header.merkle_root
+        )));
+    }
+    if !block.txdata.iter().any(|tx| tx.txid() == *txid) {
+        return Err(CapstoneError::Verification(format!(
+            "Transaction {txid} is not among the {} transactions of block {block_hash}",
+            block.txdata.len()
+        )));
+    }
+    log::info!(
+        "Merkle root {} verified over {} transactions",
+        header.merkle_root,
+        block.txdata.len()
+    );
+    Ok(())
+}
+
+/// Fail if `path` can be read or written by anyone but its owner. Always passes off Unix.
+pub fn verify_private_permissions(path: &Path) -> Result<(), CapstoneError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(CapstoneError::Verification(format!(
+                "{} holds secrets but has mode {:o}; expected 600",
+                path.display(),
+                mode & 0o777
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Value of the output paying `address` in a decoded transaction, if there is one
+fn vout_amount_for(
+    decoded_tx: &serde_json::Value,
+    address: &str,
+) -> Result<Option<Amount>, CapstoneError> {
+    let vout = decoded_tx["vout"]
+        .as_array()
+        .ok_or(bitcoincore_rpc::Error::UnexpectedStructure)?
+        .iter()
+        .find(|vout| vout["scriptPubKey"]["address"].as_str() == Some(address));
+    match vout {
+        Some(vout) => {
+            let value = vout["value"]
+                .as_f64()
+                .ok_or(bitcoincore_rpc::Error::UnexpectedStructure)?;
+            Ok(Some(btc_value_exact(value)?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Turn an error from reading `block_hash` into `CapstoneError::BlockPruned` when the node no longer
+/// has the block data; other errors pass through unchanged
+pub fn pruned_block_error(err: bitcoincore_rpc::Error, block_hash: &str) -> CapstoneError {
+    let message = err.to_string();
+    if message.contains("pruned") || message.contains("Block not available") {
+        return CapstoneError::BlockPruned {
+            block_hash: block_hash.to_string(),
+            message,
+        };
+    }
+    err.into()
+}
+
+/// The verbose (decoded) form of `txid`, looked up in the block that confirmed it
+pub fn decode_confirmed_tx(
+    rpc: &Client,
+    txid: &str,
+    block_hash: &str,
+) -> Result<serde_json::Value, CapstoneError> {
+    rpc.call::<serde_json::Value>(
+        "getrawtransaction",
+        &[json!(txid), json!(true), json!(block_hash)],
+    )
+    .map_err(|e| pruned_block_error(e, block_hash))
+}
+
+/// Re-decode the recorded transaction from the chain and check the report's amounts match its outputs exactly,
+/// and that the file at `path` ends the way `schema` expects
+pub fn verify_output(
+    rpc: &Client,
+    report: &OutReport,
+    path: &Path,
+    schema: ReportSchema,
+) -> Result<(), CapstoneError> {
+    let contents = std::fs::read_to_string(path)?;
+    // LF and CRLF files both end in '\n', so either line ending passes
+    if contents.ends_with('\n') != schema.final_newline {
+        return Err(CapstoneError::Verification(format!(
+            "{} {} a trailing newline, but the schema expects {}",
+            path.display(),
+            if schema.final_newline { "lacks" } else { "has" },
+            if schema.final_newline { "one" } else { "none" }
+        )));
+    }
+
+    let decoded_tx = decode_confirmed_tx(rpc, &report.txid, &report.block_hash)?;
+
+    let checks = [
+        (
+            "trader output amount",
+            &report.trader_output_address,
+            &report.trader_output_amount,
+        ),
+        (
+            "miner change amount",
+            &report.miner_change_address,
+            &report.miner_change_amount,
+        ),
+    ];
+    for (field, address, recorded) in checks {
+        let recorded_amount = parse_btc_exact(recorded)?;
+        // A send without change records 0 BTC for a change output that does not exist
+        let on_chain = vout_amount_for(&decoded_tx, address)?
+            .or((recorded_amount == Amount::ZERO).then_some(Amount::ZERO));
+        if on_chain != Some(recorded_amount) {
+            return Err(CapstoneError::Verification(format!(
+                "Report {field} {} BTC does not match on-chain value {}",
+                recorded_amount.to_btc(),
+                on_chain.map_or("<missing output>".to_string(), |a| format!(
+                    "{} BTC",
+                    a.to_btc()
+                ))
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Recipient and change outputs of the send, as (address, value) pairs
+#[derive(Debug, Default)]
+pub struct ClassifiedOutputs {
+    pub recipients: Vec<(String, Amount)>,
+    pub change: Option<(String, Amount)>,
+}
+
+/// Value of a decoded output in whole satoshis
+fn vout_value(vout: &serde_json::Value) -> Result<Amount, CapstoneError> {
+    let value = vout["value"]
+        .as_f64()
+        .ok_or(bitcoincore_rpc::Error::UnexpectedStructure)?;
+    btc_value_exact(value)
+}
+
+/// Split the decoded outputs into recipients and change by ownership rather than amount, so change
+/// that happens to equal the payment is still told apart. Outputs paying `known_recipients` are
+/// recipients when that set is non-empty; otherwise every output the Miner does not own is.
+/// Change is the Miner-owned output left over.
+pub fn classify_outputs(
+    miner_rpc: &Client,
+    vouts: &[serde_json::Value],
+    known_recipients: &HashSet<&str>,
+) -> Result<ClassifiedOutputs, CapstoneError> {
+    let mut classified = ClassifiedOutputs::default();
+    for vout in vouts {
+        let Some(address) = vout["scriptPubKey"]["address"].as_str() else {
+            continue;
+        };
+        let value = vout_value(vout)?;
+        let miner_owned = is_mine(miner_rpc, address)?;
+
+        let is_recipient = if known_recipients.is_empty() {
+            !miner_owned
+        } else {
+            known_recipients.contains(address)
+        };
+
+        if is_recipient {
+            classified.recipients.push((address.to_string(), value));
+        } else if miner_owned && value > Amount::ZERO {
+            classified.change = Some((address.to_string(), value));
+        }
+    }
+    Ok(classified)
+}
+
+/// Treat the output at `index` as the recipient and the remaining Miner-owned output as change,
+/// bypassing the heuristics in `classify_outputs`
+pub fn classify_by_recipient_vout(
+    miner_rpc: &Client,
+    vouts: &[serde_json::Value],
+    index: usize,
+) -> Result<ClassifiedOutputs, CapstoneError> {
+    let recipient = vouts.get(index).ok_or_else(|| {
+        CapstoneError::Parse(format!(
+            "Recipient vout {index} is out of range (transaction has {} outputs)",
+            vouts.len()
+        ))
+    })?;
+    let recipient_address = recipient["scriptPubKey"]["address"]
+        .as_str()
+        .ok_or_else(|| CapstoneError::Parse(format!("Recipient vout {index} has no address")))?;
+
+    let mut classified = ClassifiedOutputs {
+        recipients: vec![(recipient_address.to_string(), vout_value(recipient)?)],
+        change: None,
+    };
+    for (i, vout) in vouts.iter().enumerate() {
+        if i == index {
+            continue;
+        }
+        if let Some(address) = vout["scriptPubKey"]["address"].as_str() {
+            if is_mine(miner_rpc, address)? {
+                classified.change = Some((address.to_string(), vout_value(vout)?));
+            }
+        }
+    }
+    Ok(classified)
+}
+
+// How often `wait_for_confirmation` checks on a transaction
+const CONFIRMATION_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Poll until a transaction has `min_confs` confirmations, for chains where we cannot mine on demand.
+/// Returns the hash of the block that confirmed it.
+pub fn wait_for_confirmation(
+    rpc: &Client,
+    txid: &str,
+    min_confs: i64,
+    timeout: Duration,
+) -> bitcoincore_rpc::Result<String> {
+    #[derive(Deserialize)]
+    struct ConfirmedTx {
+        confirmations: i64,
+        blockhash: Option<String>,
+    }
+
+    let started = Instant::now();
+    loop {
+        let tx = rpc.call::<ConfirmedTx>("gettransaction", &[json!(txid)])?;
+        if tx.confirmations >= min_confs {
+            if let Some(blockhash) = tx.blockhash {
+                return Ok(blockhash);
+            }
+        }
+        if started.elapsed() >= timeout {
+            return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                "Timed out after {}s waiting for {txid} to confirm (last seen {} confirmations)",
+                timeout.as_secs(),
+                tx.confirmations
+            )));
+        }
+        log::info!("Waiting for confirmation ({} so far)...", tx.confirmations);
+        std::thread::sleep(CONFIRMATION_POLL_INTERVAL);
+    }
+}
+
+/// Decoded form of `txid`: from the wallet when it knows the transaction, otherwise from the node
+/// (which needs `-txindex` for confirmed transactions outside the wallet). `None` if neither has it.
+pub fn decoded_tx(rpc: &Client, txid: &str) -> Option<serde_json::Value> {
+    rpc.call::<serde_json::Value>("gettransaction", &[json!(txid), json!(true), json!(true)])
+        .ok()
+        .map(|tx| tx["decoded"].clone())
+        .or_else(|| {
+            rpc.call::<serde_json::Value>("getrawtransaction", &[json!(txid), json!(true)])
+                .ok()
+        })
+}
+
+/// Values of a transaction's inputs and of its outputs
+pub type InputOutputAmounts = (Vec<Amount>, Vec<Amount>);
+
+/// Values of the outputs `txid` spends and of the outputs it creates, resolving each input through
+/// its previous transaction. Logs why and returns `None` when a transaction cannot be looked up.
+pub fn tx_input_output_amounts(
+    rpc: &Client,
+    txid: &bitcoincore_rpc::bitcoin::Txid,
+) -> Result<Option<InputOutputAmounts>, CapstoneError> {
+    let unavailable = |what: String| {
+        log::warn!("Cannot rebuild the amounts of {txid}: {what}");
+        Ok(None)
+    };
+    let btc = |value: &serde_json::Value| match value.as_f64() {
+        Some(btc) => btc_value_exact(btc),
+        None => Err(CapstoneError::Verification(format!(
+            "{txid} spends or creates an output with invalid value {value}"
+        ))),
+    };
+    let Some(tx) = decoded_tx(rpc, &txid.to_string()) else {
+        return unavailable("transaction not found".to_string());
+    };
+
+    let mut inputs = Vec::new();
+    for vin in tx["vin"].as_array().into_iter().flatten() {
+        let (Some(prev_txid), Some(vout)) = (vin["txid"].as_str(), vin["vout"].as_u64()) else {
+            return unavailable("it has an input without a previous output".to_string());
+        };
+        let Some(prev) = decoded_tx(rpc, prev_txid) else {
+            return unavailable(format!("previous transaction {prev_txid} not found"));
+        };
+        inputs.push(btc(&prev["vout"][vout as usize]["value"])?);
+    }
+
+    let outputs = tx["vout"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|vout| btc(&vout["value"]))
+        .collect::<Result<_, CapstoneError>>()?;
+    Ok(Some((inputs, outputs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_distinct_outputs_rejects_a_shared_address() {
+        assert!(matches!(
+            verify_distinct_outputs("bcrt1qsame", "bcrt1qsame"),
+            Err(CapstoneError::Verification(_))
+        ));
+        assert!(verify_distinct_outputs("bcrt1qtrader", "bcrt1qchange").is_ok());
+    }
+}
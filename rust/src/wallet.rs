@@ -0,0 +1,1004 @@
+//! Creating, loading and checking the Miner and Trader wallets, and backing up their descriptors
+
+use crate::mining::WALLET_COINBASE_MATURITY;
+use crate::verify::{tx_confirmations, verify_private_permissions};
+use crate::{rpc_error_code, CapstoneError, RPC_WALLET_ALREADY_EXISTS, RPC_WALLET_ALREADY_LOADED};
+use bitcoin::{Address, Network};
+use bitcoincore_rpc::bitcoin::Amount;
+use bitcoincore_rpc::json::AddressType;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The wallet RPCs the setup helpers rely on, so they can run against a stand-in instead of a live node
+pub trait WalletOps {
+    /// Create a wallet with the node's default options
+    fn create_wallet(&self, wallet_name: &str) -> bitcoincore_rpc::Result<()>;
+    fn load_wallet(&self, wallet_name: &str) -> bitcoincore_rpc::Result<()>;
+    fn list_wallets(&self) -> bitcoincore_rpc::Result<Vec<String>>;
+    /// Trusted balance of the wallet the client is routed to
+    fn get_balance(&self) -> bitcoincore_rpc::Result<Amount>;
+}
+
+impl WalletOps for Client {
+    fn create_wallet(&self, wallet_name: &str) -> bitcoincore_rpc::Result<()> {
+        RpcApi::create_wallet(self, wallet_name, None, None, None, None).map(|_| ())
+    }
+
+    fn load_wallet(&self, wallet_name: &str) -> bitcoincore_rpc::Result<()> {
+        RpcApi::load_wallet(self, wallet_name).map(|_| ())
+    }
+
+    fn list_wallets(&self) -> bitcoincore_rpc::Result<Vec<String>> {
+        RpcApi::list_wallets(self)
+    }
+
+    fn get_balance(&self) -> bitcoincore_rpc::Result<Amount> {
+        RpcApi::get_balance(self, None, None)
+    }
+}
+
+/// Check if a wallet is already loaded
+pub fn is_wallet_loaded(rpc: &impl WalletOps, wallet_name: &str) -> bool {
+    match rpc.list_wallets() {
+        Ok(wallets) => wallets.contains(&wallet_name.to_string()),
+        Err(_) => false,
+    }
+}
+
+/// Create or load a wallet with the given name
+pub fn create_or_load_wallet(
+    rpc: &impl WalletOps,
+    wallet_name: &str,
+) -> bitcoincore_rpc::Result<bool> {
+    // First check if the wallet is already loaded
+    if is_wallet_loaded(rpc, wallet_name) {
+        log::info!("Wallet '{wallet_name}' is already loaded");
+        return Ok(false);
+    }
+
+    // Try to create the wallet first (this handles most cases cleanly)
+    match rpc.create_wallet(wallet_name) {
+        Ok(_) => {
+            log::info!("Wallet '{wallet_name}' created successfully");
+            Ok(true) // Wallet was created
+        }
+        // Another client loaded it between `listwallets` and `createwallet`
+        Err(create_err) if rpc_error_code(&create_err) == Some(RPC_WALLET_ALREADY_LOADED) => {
+            log::info!("Wallet '{wallet_name}' is already loaded");
+            Ok(false)
+        }
+        // If creation fails due to existing wallet, try to load it
+        Err(create_err) if rpc_error_code(&create_err) == Some(RPC_WALLET_ALREADY_EXISTS) => {
+            log::info!("Wallet '{wallet_name}' already exists, trying to load it");
+            match rpc.load_wallet(wallet_name) {
+                Ok(_) => {
+                    log::info!("Wallet '{wallet_name}' loaded successfully");
+                    Ok(false)
+                }
+                Err(load_err) => {
+                    log::warn!("Could not load wallet '{wallet_name}': {load_err}");
+                    // Continue anyway, the wallet might be usable
+                    Ok(false)
+                }
+            }
+        }
+        Err(create_err) => Err(create_err),
+    }
+}
+
+/// Create or load `base_name`, moving on to `base_name-2`, `base_name-3`, ... when the wallet exists
+/// but cannot be loaded (e.g. it is locked by another process).
+/// Returns the wallet name actually used and whether it was created.
+pub fn create_or_load_wallet_with_suffix(
+    rpc: &impl WalletOps,
+    base_name: &str,
+    max_attempts: u32,
+) -> bitcoincore_rpc::Result<(String, bool)> {
+    for attempt in 1..=max_attempts {
+        let wallet_name = if attempt == 1 {
+            base_name.to_string()
+        } else {
+            format!("{base_name}-{attempt}")
+        };
+
+        if is_wallet_loaded(rpc, &wallet_name) {
+            log::info!("Wallet '{wallet_name}' is already loaded");
+            return Ok((wallet_name, false));
+        }
+
+        match rpc.create_wallet(&wallet_name) {
+            Ok(_) => {
+                log::info!("Wallet '{wallet_name}' created successfully");
+                return Ok((wallet_name, true));
+            }
+            Err(create_err) if rpc_error_code(&create_err) == Some(RPC_WALLET_ALREADY_LOADED) => {
+                log::info!("Wallet '{wallet_name}' is already loaded");
+                return Ok((wallet_name, false));
+            }
+            Err(create_err) if rpc_error_code(&create_err) == Some(RPC_WALLET_ALREADY_EXISTS) => {
+                match rpc.load_wallet(&wallet_name) {
+                    Ok(_) => {
+                        log::info!("Wallet '{wallet_name}' loaded successfully");
+                        return Ok((wallet_name, false));
+                    }
+                    Err(load_err)
+                        if rpc_error_code(&load_err) == Some(RPC_WALLET_ALREADY_LOADED) =>
+                    {
+                        log::info!("Wallet '{wallet_name}' is already loaded");
+                        return Ok((wallet_name, false));
+                    }
+                    Err(load_err) => {
+                        log::warn!("Wallet '{wallet_name}' exists but could not be loaded ({load_err}), trying the next name");
+                    }
+                }
+            }
+            Err(create_err) => return Err(create_err),
+        }
+    }
+
+    Err(bitcoincore_rpc::Error::ReturnedError(format!(
+        "Could not create or load a wallet named '{base_name}' after {max_attempts} attempts"
+    )))
+}
+
+/// Name of the wallet a wallet-specific client is actually talking to
+pub fn client_wallet_name(rpc: &Client) -> bitcoincore_rpc::Result<String> {
+    #[derive(Deserialize)]
+    struct WalletName {
+        walletname: String,
+    }
+    Ok(rpc.call::<WalletName>("getwalletinfo", &[])?.walletname)
+}
+
+/// Guard against both per-wallet clients pointing at the same wallet, which would turn the
+/// Miner -> Trader transfer into a silent self-send
+pub fn verify_wallet_clients(
+    miner_rpc: &Client,
+    miner_wallet: &str,
+    trader_rpc: &Client,
+    trader_wallet: &str,
+) -> Result<(), CapstoneError> {
+    let miner_actual = client_wallet_name(miner_rpc)?;
+    let trader_actual = client_wallet_name(trader_rpc)?;
+
+    if miner_actual != miner_wallet || trader_actual != trader_wallet {
+        return Err(CapstoneError::Verification(format!(
+            "Wallet clients are misconfigured: expected '{miner_wallet}'/'{trader_wallet}', got '{miner_actual}'/'{trader_actual}'"
+        )));
+    }
+    if miner_actual == trader_actual {
+        return Err(CapstoneError::Verification(format!(
+            "Miner and Trader clients both point at wallet '{miner_actual}'"
+        )));
+    }
+    Ok(())
+}
+
+/// Warn when a wallet uses the legacy (non-descriptor) format. Returns whether it is a descriptor wallet.
+pub fn warn_if_legacy_wallet(rpc: &Client, wallet_name: &str) -> bitcoincore_rpc::Result<bool> {
+    // The typed `get_wallet_info` result does not expose the `descriptors` field
+    #[derive(Deserialize)]
+    struct WalletFormat {
+        // Nodes that predate descriptor wallets omit this field entirely
+        descriptors: Option<bool>,
+    }
+    let info = rpc.call::<WalletFormat>("getwalletinfo", &[])?;
+    let is_descriptor = info.descriptors.unwrap_or(false);
+    if !is_descriptor {
+        log::warn!(
+            "Wallet '{wallet_name}' is a legacy wallet; descriptor-based features will be unavailable"
+        );
+    }
+    Ok(is_descriptor)
+}
+
+/// Check whether an address belongs to the wallet behind `rpc`, per `getaddressinfo`'s `ismine`
+pub fn is_mine(rpc: &Client, address: &str) -> bitcoincore_rpc::Result<bool> {
+    #[derive(Deserialize)]
+    struct AddressOwnership {
+        ismine: bool,
+    }
+    let info = rpc.call::<AddressOwnership>("getaddressinfo", &[json!(address)])?;
+    Ok(info.ismine)
+}
+
+/// Fail unless the wallet behind `rpc` (named `wallet_name`) owns `address`
+pub fn ensure_owned(rpc: &Client, wallet_name: &str, address: &str) -> Result<(), CapstoneError> {
+    if !is_mine(rpc, address)? {
+        return Err(CapstoneError::WalletSetup(format!(
+            "'{wallet_name}' does not own {address}, so coins sent there would not be spendable by it"
+        )));
+    }
+    Ok(())
+}
+
+/// Import `descriptor` into the wallet behind `rpc` with `importdescriptors`, so coins paid to it
+/// count toward the wallet's balance. Wallets with private keys only accept descriptors that carry them.
+pub fn import_descriptor(rpc: &Client, descriptor: &str) -> Result<(), CapstoneError> {
+    #[derive(Deserialize)]
+    struct DescriptorInfo {
+        checksum: String,
+        isrange: bool,
+    }
+    #[derive(Deserialize)]
+    struct ImportError {
+        message: String,
+    }
+    #[derive(Deserialize)]
+    struct ImportResult {
+        success: bool,
+        error: Option<ImportError>,
+    }
+
+    let info = rpc.call::<DescriptorInfo>("getdescriptorinfo", &[json!(descriptor)])?;
+    // `getdescriptorinfo`'s own descriptor drops private keys, so checksum the one we were given
+    let descriptor = match descriptor.split_once('#') {
+        Some(_) => descriptor.to_string(),
+        None => format!("{descriptor}#{}", info.checksum),
+    };
+    let mut request = json!({ "desc": descriptor, "timestamp": "now" });
+    if info.isrange {
+        request["range"] = json!([0, 0]);
+    }
+    let results = rpc.call::<Vec<ImportResult>>("importdescriptors", &[json!([request])])?;
+    match results.into_iter().next() {
+        Some(result) if result.success => Ok(()),
+        Some(result) => Err(CapstoneError::WalletSetup(format!(
+            "Could not import the coinbase descriptor: {}",
+            result
+                .error
+                .map_or("no reason given".to_string(), |e| e.message)
+        ))),
+        None => Err(CapstoneError::WalletSetup(
+            "importdescriptors returned no result".to_string(),
+        )),
+    }
+}
+
+/// Script type (p2wpkh, p2tr, ...) of an address string, if it can be parsed
+pub fn address_type(address: &str) -> Option<bitcoin::AddressType> {
+    Address::from_str(address)
+        .ok()?
+        .assume_checked()
+        .address_type()
+}
+
+/// Parse an address string and check it belongs to `network`
+pub fn checked_address(address: &str, network: Network) -> Result<Address, CapstoneError> {
+    let invalid =
+        |reason: String| CapstoneError::Parse(format!("Invalid address '{address}': {reason}"));
+    Address::from_str(address)
+        .map_err(|e| invalid(e.to_string()))?
+        .require_network(network)
+        .map_err(|e| invalid(e.to_string()))
+}
+
+/// Everything a wallet holds, including unconfirmed and immature coins
+pub fn wallet_total_balance(rpc: &Client) -> bitcoincore_rpc::Result<Amount> {
+    let balances = rpc.get_balances()?;
+    Ok(balances.mine.trusted + balances.mine.untrusted_pending + balances.mine.immature)
+}
+
+/// Log `getbalance` next to the `getbalances` breakdown for `wallet`. `getbalance` only counts
+/// trusted coins, so unconfirmed payments from others and immature coinbases show up in `getbalances`
+/// alone; a gap between `getbalance` and the trusted figure itself is unexpected and warned about.
+pub fn explain_balance_difference(rpc: &Client, wallet: &str) -> bitcoincore_rpc::Result<()> {
+    let balance = RpcApi::get_balance(rpc, None, None)?;
+    let balances = rpc.get_balances()?.mine;
+    let total = balances.trusted + balances.untrusted_pending + balances.immature;
+    log::info!("'{wallet}' getbalance: {} BTC", balance.to_btc());
+    log::info!(
+        "'{wallet}' getbalances: {} BTC trusted + {} BTC untrusted pending + {} BTC immature = {} BTC",
+        balances.trusted.to_btc(),
+        balances.untrusted_pending.to_btc(),
+        balances.immature.to_btc(),
+        total.to_btc()
+    );
+    log::info!(
+        "'{wallet}' difference: {} BTC not yet spendable",
+        (total - balance.min(total)).to_btc()
+    );
+    if balance != balances.trusted {
+        log::warn!(
+            "'{wallet}' getbalance ({} BTC) disagrees with getbalances trusted ({} BTC)",
+            balance.to_btc(),
+            balances.trusted.to_btc()
+        );
+    }
+    Ok(())
+}
+
+/// Create (or truncate) `path` readable and writable by the owner only, for output that may hold secrets
+pub fn create_private_file(path: &Path) -> std::io::Result<File> {
+    let file = File::create(path)?;
+    // `File::create` keeps the mode of a file that already exists, so tighten it explicitly
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(file)
+}
+
+/// Export a wallet's descriptors to `path` as JSON, including private keys when the wallet allows it
+pub fn backup_descriptors(rpc: &Client, path: &Path) -> Result<(), CapstoneError> {
+    // `listdescriptors true` fails for locked or watch-only wallets, so fall back to public descriptors
+    let (descriptors, with_private_keys) =
+        match rpc.call::<serde_json::Value>("listdescriptors", &[json!(true)]) {
+            Ok(descriptors) => (descriptors, true),
+            Err(err) => {
+                log::warn!(
+                "Could not export private descriptors ({err}), exporting public descriptors only"
+            );
+                (
+                    rpc.call::<serde_json::Value>("listdescriptors", &[json!(false)])?,
+                    false,
+                )
+            }
+        };
+
+    // Public descriptors are fine with the default mode; private keys are for the owner's eyes only
+    let mut file = if with_private_keys {
+        create_private_file(path)?
+    } else {
+        File::create(path)?
+    };
+    writeln!(file, "{}", serde_json::to_string_pretty(&descriptors)?)?;
+
+    if with_private_keys {
+        verify_private_permissions(path)?;
+        log::warn!("!!! WARNING: {} contains PRIVATE KEYS. Anyone with this file can spend the wallet's funds. !!!", path.display());
+    }
+    log::info!("Descriptors written to {}", path.display());
+    Ok(())
+}
+
+/// Import the descriptors from a `backup_descriptors` file into the wallet behind `rpc`
+pub fn restore_descriptors(rpc: &Client, path: &Path) -> bitcoincore_rpc::Result<()> {
+    let backup: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    let descriptors = backup["descriptors"]
+        .as_array()
+        .ok_or(bitcoincore_rpc::Error::UnexpectedStructure)?;
+
+    // `listdescriptors` entries map almost one-to-one onto `importdescriptors` requests
+    let requests: Vec<serde_json::Value> = descriptors
+        .iter()
+        .map(|d| {
+            let mut request = json!({
+                "desc": d["desc"],
+                "timestamp": d["timestamp"],
+                "active": d["active"].as_bool().unwrap_or(false),
+            });
+            if let Some(internal) = d["internal"].as_bool() {
+                request["internal"] = json!(internal);
+            }
+            if !d["range"].is_null() {
+                request["range"] = d["range"].clone();
+            }
+            if !d["next"].is_null() {
+                request["next_index"] = d["next"].clone();
+            }
+            request
+        })
+        .collect();
+
+    #[derive(Deserialize)]
+    struct ImportResult {
+        success: bool,
+        error: Option<serde_json::Value>,
+    }
+    let results = rpc.call::<Vec<ImportResult>>("importdescriptors", &[json!(requests)])?;
+
+    let mut failures = 0;
+    for (request, result) in requests.iter().zip(&results) {
+        if !result.success {
+            failures += 1;
+            log::warn!(
+                "Failed to import {}: {}",
+                request["desc"],
+                result
+                    .error
+                    .as_ref()
+                    .map_or(json!(null), |e| e["message"].clone())
+            );
+        }
+    }
+    if failures > 0 {
+        return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+            "{failures} of {} descriptors failed to import",
+            results.len()
+        )));
+    }
+
+    log::info!(
+        "Imported {} descriptors from {}",
+        results.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Send `total` split evenly across `count` fresh Trader addresses in a single `sendmany` transaction.
+/// Returns the txid together with the recipient addresses that were generated.
+pub fn send_fanout(
+    miner_rpc: &Client,
+    mut new_trader_address: impl FnMut() -> bitcoincore_rpc::Result<String>,
+    count: usize,
+    total: Amount,
+    conf_target: Option<u16>,
+    fee_rate: Option<f64>,
+    preflight: bool,
+) -> Result<(String, Vec<String>), CapstoneError> {
+    let mut outputs = Vec::with_capacity(count);
+    for amount in fanout_amounts(total, count) {
+        outputs.push((new_trader_address()?, amount));
+    }
+
+    let txid = if preflight {
+        send_preflighted(miner_rpc, &outputs, conf_target, fee_rate)?
+    } else {
+        send_many(miner_rpc, &outputs, conf_target, fee_rate)?
+    };
+    let recipients = outputs.into_iter().map(|(address, _)| address).collect();
+    Ok((txid, recipients))
+}
+
+/// Split `total` into `count` equal outputs, the last one also taking the satoshis that don't divide
+/// evenly so none of `total` ends up in the fee
+pub fn fanout_amounts(total: Amount, count: usize) -> Vec<Amount> {
+    let count = count.max(1) as u64;
+    let share = total.to_sat() / count;
+    let remainder = total.to_sat() % count;
+    (0..count)
+        .map(|i| {
+            Amount::from_sat(if i + 1 == count {
+                share + remainder
+            } else {
+                share
+            })
+        })
+        .collect()
+}
+
+/// Fresh address from the wallet behind `rpc`, of `address_type` or else the wallet's default type
+pub fn new_address(
+    rpc: &Client,
+    label: &str,
+    address_type: Option<AddressType>,
+) -> bitcoincore_rpc::Result<String> {
+    Ok(rpc
+        .get_new_address(Some(label), address_type)?
+        .assume_checked()
+        .to_string())
+}
+
+/// Fund and sign a payment to every `(address, amount)` with `send`, but keep it out of the wallet
+/// and mempool so it can be checked before broadcasting. Returns the signed transaction's hex.
+pub fn build_unbroadcast(
+    rpc: &Client,
+    outputs: &[(String, Amount)],
+    conf_target: Option<u16>,
+    fee_rate: Option<f64>,
+) -> bitcoincore_rpc::Result<String> {
+    #[derive(Deserialize)]
+    struct UnbroadcastSend {
+        complete: bool,
+        hex: Option<String>,
+    }
+    let outputs: Vec<serde_json::Value> = outputs
+        .iter()
+        .map(|(address, amount)| json!({ address: amount.to_btc() }))
+        .collect();
+    let result = rpc.call::<UnbroadcastSend>(
+        "send",
+        &[
+            json!(outputs),
+            json!(conf_target),
+            json!(null),
+            json!(fee_rate),
+            json!({ "add_to_wallet": false }),
+        ],
+    )?;
+    match result.hex {
+        Some(hex) if result.complete => Ok(hex),
+        _ => Err(bitcoincore_rpc::Error::ReturnedError(
+            "send did not return a fully signed transaction".to_string(),
+        )),
+    }
+}
+
+/// Build and fund, but never sign or broadcast, a transaction paying every `(address, amount)`, then
+/// print its outputs and fee. `fundrawtransaction` leaves the chosen coins unlocked, so the wallet is untouched.
+pub fn preview_send(
+    rpc: &Client,
+    outputs: &[(String, Amount)],
+    conf_target: Option<u16>,
+    fee_rate: Option<f64>,
+) -> bitcoincore_rpc::Result<()> {
+    #[derive(Deserialize)]
+    struct FundedTx {
+        hex: String,
+        fee: f64,
+        changepos: i64,
+    }
+    let outputs: serde_json::Map<String, serde_json::Value> = outputs
+        .iter()
+        .map(|(address, amount)| (address.clone(), json!(amount.to_btc())))
+        .collect();
+    let unfunded = rpc.call::<String>("createrawtransaction", &[json!([]), json!(outputs)])?;
+
+    let mut options = serde_json::Map::new();
+    if let Some(conf_target) = conf_target {
+        options.insert("conf_target".to_string(), json!(conf_target));
+    }
+    if let Some(fee_rate) = fee_rate {
+        options.insert("fee_rate".to_string(), json!(fee_rate));
+    }
+    let funded = rpc.call::<FundedTx>("fundrawtransaction", &[json!(unfunded), json!(options)])?;
+    let decoded = rpc.call::<serde_json::Value>("decoderawtransaction", &[json!(funded.hex)])?;
+
+    println!("{}", preview_text(&decoded, funded.changepos, funded.fee));
+    Ok(())
+}
+
+/// Table of the outputs of a `decoderawtransaction` result, marking `changepos`, followed by the
+/// input count, vsize and `fee`
+pub fn preview_text(decoded: &serde_json::Value, changepos: i64, fee: f64) -> String {
+    let mut lines = vec![
+        String::new(),
+        "=== Dry run: transaction not broadcast ===".to_string(),
+        format!("{:>4} {:<64} {:>14}", "vout", "address", "amount (BTC)"),
+    ];
+    for vout in decoded["vout"].as_array().into_iter().flatten() {
+        let n = vout["n"].as_i64().unwrap_or(-1);
+        lines.push(format!(
+            "{:>4} {:<64} {:>14.8}{}",
+            n,
+            vout["scriptPubKey"]["address"]
+                .as_str()
+                .unwrap_or("<no address>"),
+            vout["value"].as_f64().unwrap_or_default(),
+            if n == changepos { "  (change)" } else { "" }
+        ));
+    }
+    lines.push(format!(
+        "Inputs: {}, vsize: {} vB, fee: {} BTC",
+        decoded["vin"].as_array().map_or(0, Vec::len),
+        decoded["vsize"].as_u64().unwrap_or_default(),
+        fee
+    ));
+    lines.join("\n")
+}
+
+/// Pay `amount` to `recipient` spending the chosen `outpoint`, with the wallet adding inputs only to
+/// cover any shortfall and the fee. Fails if the outpoint is unknown, spent, or an immature coinbase.
+pub fn send_from_utxo(
+    rpc: &Client,
+    outpoint: (bitcoincore_rpc::bitcoin::Txid, u32),
+    recipient: &str,
+    amount: Amount,
+) -> Result<bitcoincore_rpc::bitcoin::Txid, CapstoneError> {
+    #[derive(Deserialize)]
+    struct FundedTx {
+        hex: String,
+    }
+    #[derive(Deserialize)]
+    struct SignedTx {
+        hex: String,
+        complete: bool,
+    }
+    let (txid, vout) = outpoint;
+
+    let utxo = rpc.get_tx_out(&txid, vout, Some(false))?.ok_or_else(|| {
+        CapstoneError::Verification(format!("{txid}:{vout} is not an unspent output"))
+    })?;
+    if utxo.coinbase && (utxo.confirmations as u64) < WALLET_COINBASE_MATURITY {
+        return Err(CapstoneError::Verification(format!(
+            "{txid}:{vout} is an immature coinbase ({} of {WALLET_COINBASE_MATURITY} confirmations)",
+            utxo.confirmations
+        )));
+    }
+    log::info!(
+        "Spending {txid}:{vout} ({} BTC, {} confirmations)",
+        utxo.value.to_btc(),
+        utxo.confirmations
+    );
+
+    let unfunded = rpc.call::<String>(
+        "createrawtransaction",
+        &[
+            json!([{ "txid": txid.to_string(), "vout": vout }]),
+            json!({ recipient: amount.to_btc() }),
+        ],
+    )?;
+    // The chosen input stays; the wallet tops it up only when it alone cannot pay `amount` plus the fee
+    let funded = rpc.call::<FundedTx>(
+        "fundrawtransaction",
+        &[json!(unfunded), json!({ "add_inputs": true })],
+    )?;
+    let signed = rpc.call::<SignedTx>("signrawtransactionwithwallet", &[json!(funded.hex)])?;
+    if !signed.complete {
+        return Err(CapstoneError::Verification(format!(
+            "Could not fully sign the transaction spending {txid}:{vout}"
+        )));
+    }
+    Ok(rpc.call::<bitcoincore_rpc::bitcoin::Txid>("sendrawtransaction", &[json!(signed.hex)])?)
+}
+
+/// Replace the unconfirmed wallet transaction `txid` with one paying `new_fee_rate` sat/vB via
+/// `bumpfee`. Returns the replacement's txid; fails up front if `txid` did not signal BIP 125.
+pub fn bump_fee(
+    rpc: &Client,
+    txid: &bitcoincore_rpc::bitcoin::Txid,
+    new_fee_rate: u64,
+) -> Result<bitcoincore_rpc::bitcoin::Txid, CapstoneError> {
+    #[derive(Deserialize)]
+    struct Replaceability {
+        #[serde(rename = "bip125-replaceable")]
+        replaceable: String,
+    }
+    #[derive(Deserialize)]
+    struct BumpFeeResult {
+        txid: bitcoincore_rpc::bitcoin::Txid,
+        origfee: f64,
+        fee: f64,
+    }
+
+    let replaceable = rpc
+        .call::<Replaceability>("gettransaction", &[json!(txid.to_string())])?
+        .replaceable;
+    if replaceable != "yes" {
+        return Err(CapstoneError::Verification(format!(
+            "{txid} is not replaceable (bip125-replaceable: {replaceable}); send it with --rbf to bump its fee"
+        )));
+    }
+
+    let bumped = rpc.call::<BumpFeeResult>(
+        "bumpfee",
+        &[json!(txid.to_string()), json!({ "fee_rate": new_fee_rate })],
+    )?;
+    log::info!(
+        "Bumped fee from {} to {} BTC: {txid} replaced by {}",
+        bumped.origfee,
+        bumped.fee,
+        bumped.txid
+    );
+    Ok(bumped.txid)
+}
+
+/// Build the payment unbroadcast, have the node validate it with `testmempoolaccept`, and only
+/// broadcast it if it would be accepted. Returns the txid.
+pub fn send_preflighted(
+    rpc: &Client,
+    outputs: &[(String, Amount)],
+    conf_target: Option<u16>,
+    fee_rate: Option<f64>,
+) -> Result<String, CapstoneError> {
+    let raw_hex = build_unbroadcast(rpc, outputs, conf_target, fee_rate)?;
+    if !test_mempool_accept(rpc, &raw_hex)? {
+        return Err(CapstoneError::Verification(
+            "The node would reject the payment; not broadcasting it".to_string(),
+        ));
+    }
+    Ok(rpc.call::<String>("sendrawtransaction", &[json!(raw_hex)])?)
+}
+
+/// Ask the node whether it would accept `raw_hex` into its mempool, logging the verdict and
+/// the reject reason when there is one
+pub fn test_mempool_accept(rpc: &Client, raw_hex: &str) -> bitcoincore_rpc::Result<bool> {
+    #[derive(Deserialize)]
+    struct MempoolAcceptResult {
+        txid: String,
+        allowed: bool,
+        #[serde(rename = "reject-reason")]
+        reject_reason: Option<String>,
+    }
+    let results = rpc.call::<Vec<MempoolAcceptResult>>("testmempoolaccept", &[json!([raw_hex])])?;
+    let result = results
+        .into_iter()
+        .next()
+        .ok_or(bitcoincore_rpc::Error::UnexpectedStructure)?;
+    log::info!(
+        "testmempoolaccept for {}: allowed = {}",
+        result.txid,
+        result.allowed
+    );
+    if let Some(reason) = &result.reject_reason {
+        log::info!("  reject-reason: {reason}");
+    }
+    Ok(result.allowed)
+}
+
+/// Pay every `(address, amount)` in one transaction with `sendmany`
+pub fn send_many(
+    rpc: &Client,
+    outputs: &[(String, Amount)],
+    conf_target: Option<u16>,
+    fee_rate: Option<f64>,
+) -> bitcoincore_rpc::Result<String> {
+    let outputs: serde_json::Map<String, serde_json::Value> = outputs
+        .iter()
+        .map(|(address, amount)| (address.clone(), json!(amount.to_btc())))
+        .collect();
+
+    // sendmany takes a dummy "" account as its first argument for backwards compatibility
+    rpc.call::<String>(
+        "sendmany",
+        &[
+            json!(""),
+            json!(outputs),
+            json!(null),
+            json!(null),
+            json!(null),
+            json!(null),
+            json!(conf_target),
+            json!(null),
+            json!(fee_rate),
+        ],
+    )
+}
+
+/// Pay every recipient in one `sendmany` transaction with the wallet's default fee settings
+pub fn send_to_many(
+    rpc: &Client,
+    recipients: &[(String, Amount)],
+) -> bitcoincore_rpc::Result<bitcoincore_rpc::bitcoin::Txid> {
+    let txid = send_many(rpc, recipients, None, None)?;
+    bitcoincore_rpc::bitcoin::Txid::from_str(&txid).map_err(|e| {
+        bitcoincore_rpc::Error::ReturnedError(format!("sendmany returned an invalid txid: {e}"))
+    })
+}
+
+/// Whether a wallet UTXO can be spent yet, and why
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UtxoStatus {
+    /// Coinbase output with fewer than `WALLET_COINBASE_MATURITY` confirmations
+    Immature,
+    /// Coinbase output that has reached maturity
+    Mature,
+    /// Regular (non-coinbase) output
+    Spendable,
+}
+
+/// A wallet UTXO labelled with its maturity status
+#[derive(Debug)]
+pub struct Utxo {
+    pub txid: String,
+    pub vout: u32,
+    pub amount: f64,
+    pub confirmations: u64,
+    pub coinbase: bool,
+    pub status: UtxoStatus,
+}
+
+/// List the wallet's UTXOs, including immature coinbase outputs that `listunspent` leaves out
+pub fn list_utxos_by_maturity(rpc: &Client) -> bitcoincore_rpc::Result<Vec<Utxo>> {
+    #[derive(Deserialize)]
+    struct Unspent {
+        txid: String,
+        vout: u32,
+        amount: f64,
+        confirmations: u64,
+    }
+    #[derive(Deserialize)]
+    struct WalletTx {
+        #[serde(default)]
+        generated: bool,
+    }
+    #[derive(Deserialize)]
+    struct ListedTx {
+        category: String,
+        txid: String,
+        vout: u32,
+        amount: f64,
+        confirmations: i64,
+    }
+
+    let mut utxos = Vec::new();
+    for unspent in rpc.call::<Vec<Unspent>>("listunspent", &[json!(0)])? {
+        let coinbase = rpc
+            .call::<WalletTx>("gettransaction", &[json!(unspent.txid)])?
+            .generated;
+        let status = if coinbase {
+            UtxoStatus::Mature
+        } else {
+            UtxoStatus::Spendable
+        };
+        utxos.push(Utxo {
+            txid: unspent.txid,
+            vout: unspent.vout,
+            amount: unspent.amount,
+            confirmations: unspent.confirmations,
+            coinbase,
+            status,
+        });
+    }
+
+    // Immature coinbases only show up in the transaction list
+    let listed = rpc.call::<Vec<ListedTx>>("listtransactions", &[json!("*"), json!(10_000)])?;
+    for tx in listed.into_iter().filter(|tx| tx.category == "immature") {
+        utxos.push(Utxo {
+            txid: tx.txid,
+            vout: tx.vout,
+            amount: tx.amount,
+            confirmations: tx.confirmations.max(0) as u64,
+            coinbase: true,
+            status: UtxoStatus::Immature,
+        });
+    }
+    Ok(utxos)
+}
+
+/// How many more blocks until a coinbase UTXO can be spent (0 once mature, or for non-coinbase outputs)
+pub fn blocks_until_mature(rpc: &Client, utxo: &Utxo) -> bitcoincore_rpc::Result<u64> {
+    if !utxo.coinbase {
+        return Ok(0);
+    }
+    // Re-read the confirmations, since the listed count goes stale as soon as another block is mined
+    let confirmations = tx_confirmations(rpc, &utxo.txid)?.max(0) as u64;
+    Ok(WALLET_COINBASE_MATURITY.saturating_sub(confirmations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// A node's wallet directory: wallets on disk, the loaded subset, and those another process
+    /// holds locked so they cannot be loaded here
+    #[derive(Default)]
+    struct MockWallets {
+        on_disk: RefCell<Vec<String>>,
+        loaded: RefCell<Vec<String>>,
+        locked: Vec<String>,
+        created: RefCell<Vec<String>>,
+    }
+
+    impl MockWallets {
+        fn with(on_disk: &[&str], loaded: &[&str], locked: &[&str]) -> Self {
+            let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+            MockWallets {
+                on_disk: RefCell::new(names(on_disk)),
+                loaded: RefCell::new(names(loaded)),
+                locked: names(locked),
+                created: RefCell::default(),
+            }
+        }
+    }
+
+    fn rpc_error(code: i32, message: &str) -> bitcoincore_rpc::Error {
+        bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Rpc(
+            bitcoincore_rpc::jsonrpc::error::RpcError {
+                code,
+                message: message.to_string(),
+                data: None,
+            },
+        ))
+    }
+
+    impl WalletOps for MockWallets {
+        fn create_wallet(&self, wallet_name: &str) -> bitcoincore_rpc::Result<()> {
+            let name = wallet_name.to_string();
+            if self.loaded.borrow().contains(&name) {
+                return Err(rpc_error(
+                    RPC_WALLET_ALREADY_LOADED,
+                    "Wallet already loaded",
+                ));
+            }
+            if self.on_disk.borrow().contains(&name) {
+                return Err(rpc_error(
+                    RPC_WALLET_ALREADY_EXISTS,
+                    "Wallet already exists",
+                ));
+            }
+            self.on_disk.borrow_mut().push(name.clone());
+            self.loaded.borrow_mut().push(name.clone());
+            self.created.borrow_mut().push(name);
+            Ok(())
+        }
+
+        fn load_wallet(&self, wallet_name: &str) -> bitcoincore_rpc::Result<()> {
+            let name = wallet_name.to_string();
+            if self.locked.contains(&name) {
+                return Err(rpc_error(-4, "Wallet file is locked by another process"));
+            }
+            if self.loaded.borrow().contains(&name) {
+                return Err(rpc_error(
+                    RPC_WALLET_ALREADY_LOADED,
+                    "Wallet already loaded",
+                ));
+            }
+            if !self.on_disk.borrow().contains(&name) {
+                return Err(rpc_error(-18, "Wallet not found"));
+            }
+            self.loaded.borrow_mut().push(name);
+            Ok(())
+        }
+
+        fn list_wallets(&self) -> bitcoincore_rpc::Result<Vec<String>> {
+            Ok(self.loaded.borrow().clone())
+        }
+
+        fn get_balance(&self) -> bitcoincore_rpc::Result<Amount> {
+            Ok(Amount::ZERO)
+        }
+    }
+
+    #[test]
+    fn create_or_load_wallet_leaves_a_loaded_wallet_alone() {
+        let rpc = MockWallets::with(&["Miner"], &["Miner"], &[]);
+        assert!(!create_or_load_wallet(&rpc, "Miner").unwrap());
+        assert!(rpc.created.borrow().is_empty());
+    }
+
+    #[test]
+    fn create_or_load_wallet_creates_a_missing_wallet() {
+        let rpc = MockWallets::default();
+        assert!(create_or_load_wallet(&rpc, "Miner").unwrap());
+        assert_eq!(*rpc.created.borrow(), ["Miner"]);
+        assert!(is_wallet_loaded(&rpc, "Miner"));
+    }
+
+    #[test]
+    fn create_or_load_wallet_loads_an_existing_wallet() {
+        let rpc = MockWallets::with(&["Miner"], &[], &[]);
+        assert!(!create_or_load_wallet(&rpc, "Miner").unwrap());
+        assert!(rpc.created.borrow().is_empty());
+        assert!(is_wallet_loaded(&rpc, "Miner"));
+    }
+
+    #[test]
+    fn create_or_load_wallet_with_suffix_skips_a_locked_wallet() {
+        let rpc = MockWallets::with(&["Miner"], &[], &["Miner"]);
+        let (name, created) = create_or_load_wallet_with_suffix(&rpc, "Miner", 3).unwrap();
+        assert_eq!(name, "Miner-2");
+        assert!(created);
+        assert!(!is_wallet_loaded(&rpc, "Miner"));
+    }
+
+    #[test]
+    fn create_or_load_wallet_with_suffix_gives_up_after_max_attempts() {
+        let rpc = MockWallets::with(&["Miner", "Miner-2"], &[], &["Miner", "Miner-2"]);
+        assert!(create_or_load_wallet_with_suffix(&rpc, "Miner", 2).is_err());
+        assert!(rpc.created.borrow().is_empty());
+    }
+
+    #[test]
+    fn fanout_amounts_keep_the_remainder() {
+        let amounts = fanout_amounts(Amount::from_btc(1.0).unwrap(), 3);
+        assert_eq!(
+            amounts,
+            [
+                Amount::from_sat(33_333_333),
+                Amount::from_sat(33_333_333),
+                Amount::from_sat(33_333_334)
+            ]
+        );
+        assert_eq!(amounts.iter().copied().sum::<Amount>(), Amount::ONE_BTC);
+    }
+
+    #[test]
+    fn preview_text_marks_the_change_output() {
+        let decoded = json!({
+            "vin": [{}, {}],
+            "vsize": 172,
+            "vout": [
+                { "n": 0, "value": 20.0, "scriptPubKey": { "address": "bcrt1qtrader" } },
+                { "n": 1, "value": 29.9999859, "scriptPubKey": { "address": "bcrt1qchange" } },
+            ],
+        });
+        let text = preview_text(&decoded, 1, 0.0000141);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[1], "=== Dry run: transaction not broadcast ===");
+        assert!(lines[3].starts_with("   0 bcrt1qtrader") && !lines[3].ends_with("(change)"));
+        assert!(lines[4].starts_with("   1 bcrt1qchange") && lines[4].ends_with("  (change)"));
+        assert!(lines[4].contains("29.99998590"));
+        assert_eq!(lines[5], "Inputs: 2, vsize: 172 vB, fee: 0.0000141 BTC");
+    }
+}